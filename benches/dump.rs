@@ -0,0 +1,74 @@
+//! Compares dump speeds for several batch sizes, against a local Redis server.
+//!
+//! Run with `cargo bench --bench dump`. The server is taken from `REDIS_URL`, and defaults
+//! to `redis://localhost:6379/15`. The database is flushed!
+use std::time::{Duration, Instant};
+
+use redis_tools::redis_dump::RedisDump;
+use url::Url;
+
+/// The number of keys of each type.
+const KEYS_PER_TYPE: usize = 20_000;
+const BATCH_SIZES: [usize; 4] = [1, 10, 100, 1000];
+
+fn main() -> Result<(), anyhow::Error> {
+    let url = Url::parse(
+        &std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379/15".to_string()),
+    )?;
+    let mut rd = match RedisDump::build().with_url(url.clone()).connect() {
+        Ok(rd) => rd,
+        Err(err) => {
+            eprintln!(
+                "Skipping the benchmark, no Redis server at {}: {}",
+                url, err
+            );
+            return Ok(());
+        }
+    };
+
+    redis::cmd("FLUSHDB").query::<()>(rd.conn_mut())?;
+    let mut pipe = redis::pipe();
+    for i in 0..KEYS_PER_TYPE {
+        pipe.cmd("SET").arg(format!("string:{}", i)).arg(i).ignore();
+        pipe.cmd("HSET")
+            .arg(format!("hash:{}", i))
+            .arg(&["a", "1", "b", "2"])
+            .ignore();
+        pipe.cmd("RPUSH")
+            .arg(format!("list:{}", i))
+            .arg(&[1, 2, 3])
+            .ignore();
+        pipe.cmd("EXPIRE")
+            .arg(format!("list:{}", i))
+            .arg(3600)
+            .ignore();
+    }
+    pipe.query::<()>(rd.conn_mut())?;
+
+    println!("Dumping {} keys from {}", 3 * KEYS_PER_TYPE, url);
+    let mut baseline: Option<Duration> = None;
+    for batch_size in BATCH_SIZES {
+        let mut rd = RedisDump::build()
+            .with_url(url.clone())
+            .with_batch_size(batch_size)
+            .connect()?;
+        let start = Instant::now();
+        let count = rd
+            .iter()
+            .try_fold(0, |count, entry| entry.map(|_| count + 1))?;
+        let elapsed = start.elapsed();
+        assert_eq!(count, 3 * KEYS_PER_TYPE);
+
+        let speedup = baseline.get_or_insert(elapsed).as_secs_f64() / elapsed.as_secs_f64();
+        println!(
+            "batch size {:>5}: {:>8.1?} ({:>9.0} keys/s, x{:.1})",
+            batch_size,
+            elapsed,
+            count as f64 / elapsed.as_secs_f64(),
+            speedup
+        );
+    }
+
+    redis::cmd("FLUSHDB").query::<()>(rd.conn_mut())?;
+    Ok(())
+}
@@ -5,53 +5,95 @@ use clap::{IntoApp, Parser};
 use cli::RedisDumpCli;
 use dotenv::dotenv;
 use redis_tools::{
-    __private::{cli_common::DbOption, utils::get_all_non_empty_dbs},
+    __private::{
+        cli_common::{credentials, key_patterns, report_key_errors, tls_options, DbOption},
+        utils::get_all_non_empty_dbs,
+    },
+    connection::SentinelOptions,
     redis_dump::{DumpFilter, RedisDump},
-    types::RedisValue,
 };
-use serde_json::Value as Json;
-use std::collections::HashMap;
-
-fn dump_json(mut rd: RedisDump) -> Result<Json, anyhow::Error> {
-    Ok(serde_json::to_value(rd.entries()?)?)
-}
-
-fn dump_all_json(mut rd: RedisDump) -> Result<Json, anyhow::Error> {
-    let mut redis_obj = HashMap::<String, RedisValue>::new();
-    let dbs = get_all_non_empty_dbs(redis::cmd("INFO").arg("keyspace").query(rd.conn_mut())?);
-    for db in dbs {
-        rd.select_db(db)?;
-        redis_obj.extend(rd.entries()?);
-    }
-
-    Ok(serde_json::to_value(redis_obj)?)
-}
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+};
 
-fn cli_main(args: RedisDumpCli) -> Result<Json, anyhow::Error> {
+fn cli_main(args: RedisDumpCli) -> Result<(), anyhow::Error> {
     let filter = if let Some(keys) = args.key_types {
         DumpFilter::Keys(keys)
     } else {
         DumpFilter::None
     };
+    let tls = tls_options(
+        args.tls_ca.as_deref(),
+        args.tls_cert.as_deref(),
+        args.tls_key.as_deref(),
+        args.insecure,
+    )?;
+    let credentials = credentials(
+        &args.url,
+        args.user,
+        args.password_file.as_deref(),
+        args.askpass,
+    )?;
+    let include = key_patterns(args.include, args.include_regex);
+    let exclude = key_patterns(args.exclude, args.exclude_regex);
 
     // Build the RedisDump object and connect to the server.
-    let mut rd = RedisDump::build()
+    let mut builder = RedisDump::build()
         .with_url(args.url)
+        .with_credentials(credentials)
+        .with_tls(tls)
+        .with_cluster(args.cluster)
+        .with_replicas(args.replica)
         .with_filter(filter)
         .with_metadata(!args.no_metadata)
-        .connect()?;
+        .with_raw_payload(args.raw_payload)
+        .with_batch_size(args.batch_size)
+        .with_chunk_size(args.chunk_size)
+        .with_keep_going(args.keep_going);
+    if let (Some(sentinels), Some(master_name)) = (args.sentinels, args.master_name) {
+        builder = builder.with_sentinel(SentinelOptions {
+            sentinels,
+            master_name,
+        });
+    }
+    if !include.is_empty() {
+        builder = builder.with_filter(DumpFilter::Include(include));
+    }
+    if !exclude.is_empty() {
+        builder = builder.with_filter(DumpFilter::Exclude(exclude));
+    }
+    let mut rd = builder.connect()?;
 
-    // Select the database if it was specified.
-    if let Some(DbOption::Db(db)) = args.db {
-        rd.select_db(db)?;
-        dump_json(rd)
-    // If `all` was specified, dump all databases.
-    } else if let Some(DbOption::All) = args.db {
-        dump_all_json(rd)
-    // If no database option was specified, the database will be selected by the URL.
-    } else {
-        dump_json(rd)
+    let dbs = match args.db {
+        Some(DbOption::Db(db)) => vec![db],
+        // A cluster only has database 0, which spans all the nodes.
+        Some(DbOption::All) if args.cluster => vec![0],
+        // If `all` was specified, dump all databases.
+        Some(DbOption::All) => {
+            get_all_non_empty_dbs(redis::cmd("INFO").arg("keyspace").query(rd.conn_mut())?)
+        }
+        // If no database option was specified, the database will be selected by the URL.
+        None => vec![rd.db()],
+    };
+
+    // Entries are written as they are read, so the dump is never held in memory. Only a file
+    // can be written back to, to add the summary to its header.
+    match args.output {
+        Some(path) => {
+            rd.write_json_seekable(&dbs, BufWriter::new(File::create(path)?), args.pretty)?
+        }
+        None => rd.write_json(&dbs, BufWriter::new(io::stdout().lock()), args.pretty)?,
+    }
+
+    let summary = rd.summary();
+    if summary.vanished > 0 || summary.retyped > 0 {
+        eprintln!(
+            "Skipped {} keys deleted during the dump, and read {} keys again after their type changed.",
+            summary.vanished, summary.retyped
+        );
     }
+    report_key_errors(rd.errors(), args.error_report.as_deref())
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -60,19 +102,13 @@ fn main() -> Result<(), anyhow::Error> {
 
     // Parse command line arguments, and run
     let args = RedisDumpCli::parse();
-    let is_pretty = args.pretty;
-    let json = cli_main(args);
-    if let Err(err) = json {
+    if let Err(err) = args.validate() {
+        err.exit();
+    }
+    if let Err(err) = cli_main(args) {
         clap::Error::raw(clap::ErrorKind::Io, err.to_string())
             .format(&mut RedisDumpCli::into_app())
             .exit(); // We explicitly exit here to apply the error formatting.
     }
-
-    // Print the JSON to stdout.
-    if is_pretty {
-        println!("{}", serde_json::to_string_pretty(&json?)?);
-    } else {
-        println!("{}", serde_json::to_string(&json?)?);
-    }
     Ok(())
 }
@@ -3,35 +3,85 @@ mod cli;
 use clap::Parser;
 use cli::RedisRestoreCli;
 use dotenv::dotenv;
-use redis_tools::__private::utils::print_red_error;
-use redis_tools::redis_restore::RedisRestore;
-use redis_tools::types::RedisValue;
-use std::io::Write;
-
-use std::{
-    collections::HashMap,
-    io::{self, Read},
+use redis_tools::__private::{
+    cli_common::{credentials, key_patterns, report_key_errors, tls_options, DbOption},
+    utils::print_red_error,
 };
+use redis_tools::connection::SentinelOptions;
+use redis_tools::redis_restore::{RedisRestore, RestoreFilter};
+use std::fs::File;
+use std::io::{self, Write};
 
 fn cli_main(args: RedisRestoreCli) -> Result<(), anyhow::Error> {
-    let mut buf = Vec::new();
-    if let Some(file) = args.file {
-        let mut file = std::fs::File::open(file)?;
-        file.read_to_end(&mut buf)?;
+    let file = args.file.map(File::open).transpose()?;
+
+    let filter = if let Some(keys) = args.key_types {
+        RestoreFilter::Keys(keys)
     } else {
-        io::stdin().read_to_end(&mut buf)?;
-    }
+        RestoreFilter::None
+    };
+    // Restore all databases of the dump, unless a single one was specified.
+    let source_db = match args.db {
+        Some(DbOption::Db(db)) => Some(db),
+        Some(DbOption::All) | None => None,
+    };
+
+    let tls = tls_options(
+        args.tls_ca.as_deref(),
+        args.tls_cert.as_deref(),
+        args.tls_key.as_deref(),
+        args.insecure,
+    )?;
+    let credentials = credentials(
+        &args.url,
+        args.user,
+        args.password_file.as_deref(),
+        args.askpass,
+    )?;
+    let include = key_patterns(args.include, args.include_regex);
+    let exclude = key_patterns(args.exclude, args.exclude_regex);
 
-    let redis_map: HashMap<String, RedisValue> = serde_json::from_slice(&buf)?;
     // Build the RedisRestore object and connect to the server.
-    let mut rr = RedisRestore::build()
+    let mut builder = RedisRestore::build()
         .with_url(args.url)
-        // .with_filter(args.filter)
-        .connect()?;
+        .with_credentials(credentials)
+        .with_tls(tls)
+        .with_cluster(args.cluster)
+        .with_filter(filter)
+        .with_source_db(source_db)
+        .with_conflict_policy(args.on_conflict)
+        .with_expiry_mode(args.expiry)
+        .with_skip_expired(args.skip_expired)
+        .with_batch_size(args.batch_size)
+        .with_chunk_size(args.chunk_size)
+        .with_transaction(args.transaction)
+        .with_keep_going(args.keep_going);
+    if let (Some(sentinels), Some(master_name)) = (args.sentinels, args.master_name) {
+        builder = builder.with_sentinel(SentinelOptions {
+            sentinels,
+            master_name,
+        });
+    }
+    if !include.is_empty() {
+        builder = builder.with_filter(RestoreFilter::Include(include));
+    }
+    if !exclude.is_empty() {
+        builder = builder.with_filter(RestoreFilter::Exclude(exclude));
+    }
+    let mut rr = builder.connect()?;
 
-    rr.fill_db(redis_map)?;
+    // The dump is restored while it is read, so it is never held in memory. A file is read
+    // twice when conflicts fail the restore, but stdin can't be, so it is held in memory then.
+    let stats = match file {
+        Some(file) => rr.fill_from_seekable(file)?,
+        None => rr.fill_from_reader(io::stdin().lock())?,
+    };
+    eprintln!(
+        "Restored {} keys, skipped {} filtered keys, {} existing keys and {} expired keys.",
+        stats.restored, stats.filtered, stats.existing, stats.expired
+    );
 
-    Ok(())
+    report_key_errors(rr.errors(), args.error_report.as_deref())
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -0,0 +1,117 @@
+//! Redis Cluster support: hash slots, and the topology of a cluster.
+use std::ops::RangeInclusive;
+
+use redis::Value;
+use url::Url;
+
+use crate::{connection::server_url, Error};
+
+/// The number of hash slots of a cluster.
+pub const SLOT_COUNT: u16 = 16384;
+
+/// The hash slot of a key.
+///
+/// Only the hash tag of the key is hashed, if it has one (e.g. `user` in `{user}:1`),
+/// so keys sharing a hash tag are in the same slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|&b| b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|&b| b == b'}') {
+            // An empty tag (`{}`) isn't a hash tag.
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(hashed) % SLOT_COUNT
+}
+
+/// CRC-16/XMODEM, as used by Redis Cluster.
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// A node of a cluster, and the slots it serves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterNode {
+    pub host: String,
+    pub port: u16,
+    /// Whether the node is a replica, rather than the primary of its slots.
+    pub replica: bool,
+    pub slots: Vec<RangeInclusive<u16>>,
+}
+
+impl ClusterNode {
+    /// Whether the node serves the given slot.
+    pub fn serves(&self, slot: u16) -> bool {
+        self.slots.iter().any(|slots| slots.contains(&slot))
+    }
+
+    /// The URL of the node: the URL of another node of the cluster, with the host and port of this one.
+    pub(crate) fn url(&self, seed: &Url) -> Result<Url, Error> {
+        server_url(seed, &self.host, self.port)
+    }
+}
+
+/// Discover the nodes of a cluster with `CLUSTER SLOTS`: its primaries, or a replica
+/// of each primary (the primary itself if it has none).
+///
+/// `seed_host` is used for nodes that don't know their own address.
+pub(crate) fn discover(
+    conn: &mut redis::Connection,
+    seed_host: &str,
+    replicas: bool,
+) -> Result<Vec<ClusterNode>, Error> {
+    // Each range is `[start, end, primary, replica...]`, each node being `[host, port, id, ...]`.
+    let ranges: Vec<Vec<Value>> = redis::cmd("CLUSTER").arg("SLOTS").query(conn)?;
+    let unexpected = || Error::protocol("Unexpected reply to `CLUSTER SLOTS`");
+    let mut nodes: Vec<ClusterNode> = Vec::new();
+    for range in ranges {
+        let [start, end, servers @ ..] = range.as_slice() else {
+            return Err(unexpected());
+        };
+        let (start, end): (u16, u16) = (
+            redis::from_redis_value(start)?,
+            redis::from_redis_value(end)?,
+        );
+        let servers = servers
+            .iter()
+            .map(
+                |server| match redis::from_redis_value::<Vec<Value>>(server)?.as_slice() {
+                    [host, port, ..] => Ok((
+                        redis::from_redis_value::<String>(host)?,
+                        redis::from_redis_value::<u16>(port)?,
+                    )),
+                    _ => Err(unexpected()),
+                },
+            )
+            .collect::<Result<Vec<_>, Error>>()?;
+        let ((host, port), replica) = match (servers.first(), servers.get(1)) {
+            (_, Some(replica)) if replicas => (replica.clone(), true),
+            (Some(primary), _) => (primary.clone(), false),
+            (None, _) => return Err(unexpected()),
+        };
+        // A node with an unknown address (`?` or empty) is the one that replied.
+        let host = match host.as_str() {
+            "" | "?" => seed_host.to_string(),
+            _ => host,
+        };
+        match nodes.iter_mut().find(|n| n.host == host && n.port == port) {
+            Some(node) => node.slots.push(start..=end),
+            None => nodes.push(ClusterNode {
+                host,
+                port,
+                replica,
+                slots: vec![start..=end],
+            }),
+        }
+    }
+    Ok(nodes)
+}
@@ -0,0 +1,258 @@
+//! Options of the connection to the Redis server, shared by dumps and restores.
+use std::{collections::HashMap, fmt, time::Duration};
+
+use redis::{ClientTlsConfig, ErrorKind, RedisError, TlsCertificates};
+use url::Url;
+
+use crate::Error;
+
+/// TLS options, for servers reached with a `rediss://` URL.
+///
+/// Without any option, the certificate of the server is verified with the system CAs.
+/// The options are ignored for other URLs.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// CA certificates (PEM) to verify the server with, instead of the system ones.
+    pub ca: Option<Vec<u8>>,
+    /// A client certificate and its key (PEM), for servers requiring mutual TLS.
+    pub client_cert: Option<(Vec<u8>, Vec<u8>)>,
+    /// Don't verify the certificate of the server.
+    pub insecure: bool,
+}
+
+/// Credentials to authenticate with, replacing the ones of the URL, so they aren't given on
+/// the command line.
+///
+/// With a username, the user is authenticated as an ACL user (Redis 6 or later) with
+/// `AUTH username password`; without one, as the default user with `AUTH password`.
+/// A username needs a password, given here or in the URL.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Credentials {
+    /// The URL with these credentials instead of its own ones.
+    pub(crate) fn apply(&self, url: &Url) -> Result<Url, Error> {
+        // Without a password, the redis crate doesn't authenticate at all, rather than as
+        // the user.
+        if self.username.is_some() && self.password.is_none() && !has_password(url) {
+            return Err(Error::Connection(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "A user can't authenticate without a password",
+            ))));
+        }
+        let mut url = url.clone();
+        if matches!(url.scheme(), "unix" | "redis+unix") {
+            // The redis crate reads the credentials of a Unix socket from the query.
+            let mut query = url
+                .query_pairs()
+                .into_owned()
+                .collect::<Vec<(String, String)>>();
+            for (name, value) in [("user", &self.username), ("pass", &self.password)] {
+                if let Some(value) = value {
+                    query.retain(|(n, _)| n != name);
+                    query.push((name.to_string(), value.clone()));
+                }
+            }
+            url.query_pairs_mut().clear().extend_pairs(query);
+            return Ok(url);
+        }
+        let invalid = || {
+            Error::Connection(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "Credentials can't be given to a URL without a host",
+            )))
+        };
+        if let Some(username) = &self.username {
+            url.set_username(username).map_err(|_| invalid())?;
+        }
+        if let Some(password) = &self.password {
+            url.set_password(Some(password)).map_err(|_| invalid())?;
+        }
+        Ok(url)
+    }
+}
+
+/// Whether the URL has a password.
+pub(crate) fn has_password(url: &Url) -> bool {
+    if matches!(url.scheme(), "unix" | "redis+unix") {
+        url.query_pairs().any(|(name, _)| name == "pass")
+    } else {
+        url.password().is_some()
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+/// How long a sentinel is waited for, before asking the next one.
+const SENTINEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Servers monitored by Redis Sentinel, reached through the sentinels rather than by address.
+///
+/// The URL of the dump or restore still gives the scheme, the credentials and the database:
+/// only its host and port are replaced by the ones of the server the sentinels point to.
+#[derive(Clone, Debug)]
+pub struct SentinelOptions {
+    /// The URLs of the sentinels, asked in turn (e.g. `redis://sentinel-1:26379`).
+    ///
+    /// `rediss://` sentinels are reached with the same TLS options as the server.
+    pub sentinels: Vec<Url>,
+    /// The name of the master, as monitored by the sentinels.
+    pub master_name: String,
+}
+
+/// A server found through the sentinels: their master, or one of its replicas.
+pub(crate) struct SentinelServer {
+    url: Url,
+    tls: TlsOptions,
+    options: SentinelOptions,
+    replica: bool,
+}
+
+impl SentinelServer {
+    pub(crate) fn new(url: Url, tls: TlsOptions, options: SentinelOptions, replica: bool) -> Self {
+        SentinelServer {
+            url,
+            tls,
+            options,
+            replica,
+        }
+    }
+
+    /// Connect to the server the sentinels point to.
+    ///
+    /// The master must have the master role: right after a failover, the sentinels may still
+    /// point to the former master, which is then a replica.
+    pub(crate) fn connect(&self) -> Result<redis::Connection, Error> {
+        let (host, port) = self.resolve()?;
+        let client = open_client(&server_url(&self.url, &host, port)?, &self.tls)?;
+        let mut conn = client.get_connection()?;
+        if !self.replica {
+            let role: Vec<redis::Value> = redis::cmd("ROLE").query(&mut conn)?;
+            let role = role
+                .first()
+                .map(redis::from_redis_value::<String>)
+                .transpose()?;
+            if role.as_deref() != Some("master") {
+                return Err(Error::Connection(RedisError::from((
+                    ErrorKind::MasterDown,
+                    "The master given by the sentinels isn't a master",
+                    format!("{}:{}", host, port),
+                ))));
+            }
+        }
+        Ok(conn)
+    }
+
+    /// The address of the server, from the first sentinel that knows the master.
+    fn resolve(&self) -> Result<(String, u16), Error> {
+        let mut last_err = RedisError::from((ErrorKind::EmptySentinelList, "No sentinel given"));
+        for sentinel in &self.options.sentinels {
+            match self.ask(sentinel) {
+                Ok(addr) => return Ok(addr),
+                // Whatever the sentinels reply, the server can't be reached without them.
+                Err(Error::Connection(err) | Error::Protocol { source: err, .. }) => last_err = err,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::Connection(last_err))
+    }
+
+    /// Ask a sentinel for the address of the master, or of one of its healthy replicas
+    /// (falling back to the master if it has none).
+    fn ask(&self, sentinel: &Url) -> Result<(String, u16), Error> {
+        let client = open_client(sentinel, &self.tls)?;
+        let mut conn = client.get_connection_with_timeout(SENTINEL_TIMEOUT)?;
+        let name = &self.options.master_name;
+        if self.replica {
+            let replicas: Vec<HashMap<String, String>> = redis::cmd("SENTINEL")
+                .arg("REPLICAS")
+                .arg(name)
+                .query(&mut conn)?;
+            if let Some(addr) = healthy_replica(&replicas) {
+                return Ok(addr);
+            }
+        }
+        let addr: Option<(String, u16)> = redis::cmd("SENTINEL")
+            .arg("GET-MASTER-ADDR-BY-NAME")
+            .arg(name)
+            .query(&mut conn)?;
+        addr.ok_or_else(|| {
+            Error::Connection(RedisError::from((
+                ErrorKind::MasterNameNotFoundBySentinel,
+                "Master unknown to the sentinel",
+                format!("{} ({})", name, sentinel),
+            )))
+        })
+    }
+}
+
+/// The address of the first replica that is up and in sync with its master, among the
+/// replicas listed by `SENTINEL REPLICAS`.
+pub(crate) fn healthy_replica(replicas: &[HashMap<String, String>]) -> Option<(String, u16)> {
+    replicas.iter().find_map(|replica| {
+        let field = |name: &str| replica.get(name).map(String::as_str);
+        let down = field("flags")?
+            .split(',')
+            .any(|flag| matches!(flag, "s_down" | "o_down" | "disconnected"));
+        if down || field("master-link-status") != Some("ok") {
+            return None;
+        }
+        Some((field("ip")?.to_string(), field("port")?.parse().ok()?))
+    })
+}
+
+/// Open a client to the server at the given URL.
+pub(crate) fn open_client(url: &Url, tls: &TlsOptions) -> Result<redis::Client, Error> {
+    if url.scheme() != "rediss" {
+        return Ok(redis::Client::open(url.as_str())?);
+    }
+
+    let mut url = url.clone();
+    // The redis crate reads this option from the URL only.
+    if tls.insecure {
+        url.set_fragment(Some("insecure"));
+    }
+    if tls.ca.is_none() && tls.client_cert.is_none() {
+        return Ok(redis::Client::open(url.as_str())?);
+    }
+    let certificates = TlsCertificates {
+        client_tls: tls
+            .client_cert
+            .clone()
+            .map(|(client_cert, client_key)| ClientTlsConfig {
+                client_cert,
+                client_key,
+            }),
+        root_cert: tls.ca.clone(),
+    };
+    Ok(redis::Client::build_with_tls(url.as_str(), certificates)?)
+}
+
+/// The URL of the server at the given address: the given URL (with its credentials, database
+/// and scheme), with the host and port of the server.
+pub(crate) fn server_url(url: &Url, host: &str, port: u16) -> Result<Url, Error> {
+    let invalid = || {
+        redis::RedisError::from((
+            redis::ErrorKind::InvalidClientConfig,
+            "Servers of a cluster or found through Sentinel are reached through TCP",
+            url.to_string(),
+        ))
+    };
+    if matches!(url.scheme(), "unix" | "redis+unix") {
+        return Err(invalid().into());
+    }
+    let mut url = url.clone();
+    url.set_host(Some(host)).map_err(|_| invalid())?;
+    url.set_port(Some(port)).map_err(|_| invalid())?;
+    Ok(url)
+}
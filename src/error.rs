@@ -0,0 +1,247 @@
+//! The errors returned by the library.
+use std::{fmt, io};
+
+use crate::types::RedisBytes;
+
+/// The maximum number of conflicting keys listed in a conflict error.
+const MAX_REPORTED_CONFLICTS: usize = 10;
+
+/// A `Result` whose error is an [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error of a dump or a restore.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The server can't be reached, or the connection was lost.
+    Connection(redis::RedisError),
+    /// The server refused the credentials, or required some and none were given.
+    Authentication(redis::RedisError),
+    /// The ACL of the user doesn't allow a command, or access to a key.
+    ///
+    /// `key` is the key the command was about, if known.
+    Authorization {
+        key: Option<RedisBytes>,
+        source: redis::RedisError,
+    },
+    /// A key has a type that can only be dumped as a raw payload.
+    UnsupportedType { key: RedisBytes, key_type: String },
+    /// The server failed a command, or its reply was unexpected.
+    ///
+    /// `key` is the key the command was about, if known.
+    Protocol {
+        key: Option<RedisBytes>,
+        source: redis::RedisError,
+    },
+    /// A command of a transaction failed; the other commands of the transaction were still applied.
+    Transaction {
+        keys: Vec<RedisBytes>,
+        source: redis::RedisError,
+    },
+    /// Reading or writing a dump failed.
+    Io(io::Error),
+    /// The dump is malformed, or of an unsupported format version.
+    Format(String),
+    /// Keys of the dump already exist in the target, as `(db, key)`.
+    ///
+    /// See [`crate::redis_restore::ConflictPolicy::Fail`].
+    Conflict { keys: Vec<(u32, RedisBytes)> },
+    /// Several databases of a dump were restored into a cluster, which only has database 0.
+    ///
+    /// See [`crate::redis_restore::RedisRestoreBuilder::with_source_db`] to restore a single one.
+    MultipleDatabases { dbs: Vec<u32> },
+}
+
+impl Error {
+    /// A protocol error about something else than a specific key.
+    pub(crate) fn protocol(desc: &'static str) -> Self {
+        Error::Protocol {
+            key: None,
+            source: redis::RedisError::from((redis::ErrorKind::TypeError, desc)),
+        }
+    }
+
+    /// Tie the error to the key it happened on, unless it already is.
+    pub(crate) fn with_key(self, key: &RedisBytes) -> Self {
+        match self {
+            Error::Protocol { key: None, source } => Error::Protocol {
+                key: Some(key.clone()),
+                source,
+            },
+            Error::Authorization { key: None, source } => Error::Authorization {
+                key: Some(key.clone()),
+                source,
+            },
+            Error::Format(msg) => Error::Format(format!("{}: {}", key, msg)),
+            err => err,
+        }
+    }
+
+    /// Whether the error is an error of the connection, rather than of a single key.
+    pub fn is_connection_error(&self) -> bool {
+        matches!(self, Error::Connection(_) | Error::Authentication(_))
+    }
+
+    /// Whether the error may come from a failover: the server can't be reached, or it
+    /// became a replica and refuses writes.
+    pub(crate) fn is_failover(&self) -> bool {
+        match self {
+            Error::Connection(_) => true,
+            Error::Protocol { source, .. } | Error::Transaction { source, .. } => {
+                source.kind() == redis::ErrorKind::ReadOnly
+            }
+            _ => false,
+        }
+    }
+
+    /// Report an error that may come from a failover of a server found through Sentinel as
+    /// one. The dump or restore can't go on with the new server, so it must be run again.
+    pub(crate) fn into_failover(self) -> Self {
+        if !self.is_failover() {
+            return self;
+        }
+        Error::Connection(redis::RedisError::from((
+            redis::ErrorKind::MasterDown,
+            "The server may have failed over, so the dump or restore must be run again",
+            self.to_string(),
+        )))
+    }
+
+    /// The reason of the error, without the key it happened on.
+    pub(crate) fn reason(&self) -> String {
+        match self {
+            Error::UnsupportedType { .. } => "Unsupported type".to_string(),
+            Error::Protocol { source, .. } => source.to_string(),
+            Error::Authorization { source, .. } => format!("Permission denied: {}", source),
+            err => err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connection(err) => write!(f, "{}", err),
+            Error::Authentication(err)
+                if err.kind() == redis::ErrorKind::AuthenticationFailed
+                    || err.code() == Some("WRONGPASS") =>
+            {
+                write!(
+                    f,
+                    "Authentication failed: the user or password is wrong, or the user is disabled"
+                )
+            }
+            Error::Authentication(err) => write!(
+                f,
+                "Authentication failed: the server requires a password ({})",
+                err
+            ),
+            Error::Authorization {
+                key: Some(key),
+                source,
+            } => write!(f, "{}: Permission denied: {}", key, source),
+            Error::Authorization { key: None, source } => {
+                write!(f, "Permission denied: {}", source)
+            }
+            Error::UnsupportedType { key, key_type } => {
+                write!(f, "{}: Unsupported type `{}`", key, key_type)
+            }
+            Error::Protocol {
+                key: Some(key),
+                source,
+            } => write!(f, "{}: {}", key, source),
+            Error::Protocol { key: None, source } => write!(f, "{}", source),
+            Error::Transaction { keys, source } => write!(
+                f,
+                "{} (in a transaction writing to {})",
+                source,
+                keys.iter()
+                    .map(|key| format!("`{}`", key))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Format(msg) => write!(f, "{}", msg),
+            Error::Conflict { keys } => write!(
+                f,
+                "{} key(s) already exist, nothing was restored: {}{}",
+                keys.len(),
+                keys.iter()
+                    .take(MAX_REPORTED_CONFLICTS)
+                    .map(|(db, key)| format!("`{}` (db {})", key, db))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                if keys.len() > MAX_REPORTED_CONFLICTS {
+                    ", ..."
+                } else {
+                    ""
+                }
+            ),
+            Error::MultipleDatabases { dbs } => write!(
+                f,
+                "A cluster only has database 0, but the dump has databases {}: restore them one at a time",
+                dbs.iter()
+                    .map(|db| db.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connection(err)
+            | Error::Authentication(err)
+            | Error::Authorization { source: err, .. }
+            | Error::Protocol { source: err, .. }
+            | Error::Transaction { source: err, .. } => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(err: redis::RedisError) -> Self {
+        // The redis crate reports a `NOAUTH` reply to the `SELECT` it sends when connecting
+        // as a failure to switch databases, only keeping the message of the reply.
+        let authentication = err.kind() == redis::ErrorKind::AuthenticationFailed
+            || matches!(err.code(), Some("NOAUTH" | "WRONGPASS"))
+            || err
+                .detail()
+                .is_some_and(|detail| detail.starts_with("Authentication required"));
+        if authentication {
+            Error::Authentication(err)
+        } else if err.code() == Some("NOPERM") {
+            Error::Authorization {
+                key: None,
+                source: err,
+            }
+        } else if err.is_io_error() || err.kind() == redis::ErrorKind::InvalidClientConfig {
+            Error::Connection(err)
+        } else {
+            Error::Protocol {
+                key: None,
+                source: err,
+            }
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_io() {
+            Error::Io(err.into())
+        } else {
+            Error::Format(err.to_string())
+        }
+    }
+}
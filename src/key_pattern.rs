@@ -0,0 +1,116 @@
+use regex::bytes::Regex;
+
+/// A pattern matched against key names.
+#[derive(Clone, Debug)]
+pub enum KeyPattern {
+    /// A Redis glob-style pattern, as used by `SCAN MATCH` and `KEYS`.
+    ///
+    /// Supports `*`, `?`, `[abc]`, `[^abc]`, `[a-z]`, and `\` to escape special characters.
+    Glob(String),
+    /// A regular expression, matched anywhere in the key (unless anchored).
+    Regex(Regex),
+}
+
+impl KeyPattern {
+    /// Whether the key matches the pattern.
+    pub fn is_match(&self, key: &[u8]) -> bool {
+        match self {
+            KeyPattern::Glob(glob) => glob_match(glob.as_bytes(), key),
+            KeyPattern::Regex(regex) => regex.is_match(key),
+        }
+    }
+}
+
+/// Whether the key matches any of the patterns.
+pub(crate) fn matches_any(patterns: &[KeyPattern], key: &[u8]) -> bool {
+    patterns.iter().any(|pattern| pattern.is_match(key))
+}
+
+/// Match a string against a Redis glob-style pattern.
+///
+/// This follows the semantics of `stringmatchlen` in the Redis source code. Only the last
+/// star is backtracked into (a later star can match anything an earlier one could), so
+/// matching takes at most the length of the pattern times the length of the string.
+pub(crate) fn glob_match(mut pattern: &[u8], mut string: &[u8]) -> bool {
+    // The pattern after the last star, and the string from where that star stopped matching.
+    let mut backtrack: Option<(&[u8], &[u8])> = None;
+    while !pattern.is_empty() || !string.is_empty() {
+        if let Some((b'*', rest)) = pattern.split_first() {
+            backtrack = Some((rest, string));
+            pattern = rest;
+            continue;
+        }
+        if let Some((&c, string_rest)) = string.split_first() {
+            if let Some(rest) = match_char(pattern, c) {
+                pattern = rest;
+                string = string_rest;
+                continue;
+            }
+        }
+        // Let the last star match one more character, and try again after it.
+        match backtrack {
+            Some((rest, [_, string_rest @ ..])) => {
+                backtrack = Some((rest, string_rest));
+                pattern = rest;
+                string = string_rest;
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Match a character against the start of a pattern, which isn't a star.
+///
+/// Returns the pattern after what matched the character, if it matched.
+fn match_char(pattern: &[u8], c: u8) -> Option<&[u8]> {
+    match pattern.split_first()? {
+        (b'?', rest) => Some(rest),
+        (b'[', rest) => match match_class(rest, c) {
+            (true, rest) => Some(rest),
+            (false, _) => None,
+        },
+        (b'\\', [escaped, rest @ ..]) => (*escaped == c).then_some(rest),
+        (&other, rest) => (other == c).then_some(rest),
+    }
+}
+
+/// Match a character against a `[...]` class, given the pattern right after the `[`.
+///
+/// Returns whether the character matched, and the pattern after the closing `]`.
+fn match_class(mut pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+    let mut matched = false;
+    loop {
+        match pattern {
+            // An unterminated class ends with the pattern.
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == c;
+                pattern = rest;
+            }
+            // Like in Redis, a `]` right after the `-` ends the range, not the class.
+            [start, b'-', end, rest @ ..] => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= (low..=high).contains(&c);
+                pattern = rest;
+            }
+            [other, rest @ ..] => {
+                matched |= *other == c;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negate, pattern)
+}
@@ -3,9 +3,15 @@
 #[path = "private/mod.rs"]
 pub mod __private;
 
+pub mod cluster;
+pub mod connection;
+mod error;
+pub mod key_pattern;
 pub mod redis_dump;
 pub mod redis_restore;
 pub mod types;
 
+pub use error::{Error, Result};
+
 #[cfg(test)]
 mod tests;
@@ -3,7 +3,24 @@
 /// This module contains functions that are common to the CLI binaries.
 /// Should not be used directly.
 ///
-use super::consts::REDIS_KEY_TYPE;
+use super::consts::{REDISCLI_AUTH_ENV_VAR_KEY, REDIS_KEY_TYPE};
+use crate::{
+    connection::{has_password, Credentials, TlsOptions},
+    key_pattern::KeyPattern,
+    redis_restore::{ConflictPolicy, ExpiryMode},
+    types::KeyError,
+};
+use anyhow::anyhow;
+use regex::bytes::Regex;
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufWriter, Write},
+};
+use url::Url;
+
+/// The maximum number of failed keys listed on stderr.
+const MAX_REPORTED_ERRORS: usize = 20;
 
 #[derive(Clone, Debug)]
 pub enum DbOption {
@@ -17,10 +34,17 @@ pub fn is_number_or_all(s: &str) -> Result<DbOption, String> {
     } else {
         s.parse::<u32>()
             .map(DbOption::Db)
-            .map_err(|_| format!("valid values are: <integer> | all"))
+            .map_err(|_| "valid values are: <integer> | all".to_string())
     }
 }
 
+pub fn is_positive_number(s: &str) -> Result<usize, String> {
+    s.parse::<usize>()
+        .ok()
+        .filter(|size| *size > 0)
+        .ok_or_else(|| "valid values are: <integer greater than 0>".to_string())
+}
+
 pub fn key_type_exists(s: &str) -> Result<String, String> {
     REDIS_KEY_TYPE
         .contains(&s)
@@ -33,3 +57,145 @@ pub fn key_type_exists(s: &str) -> Result<String, String> {
             )
         })
 }
+
+/// Check a key type, or the name of a type defined by a module (e.g. `ReJSON-RL`), which
+/// Redis requires to be 9 characters among `A-Z`, `a-z`, `0-9`, `-` and `_`.
+pub fn key_type_or_module_type(s: &str) -> Result<String, String> {
+    let is_module_type = s.len() == 9
+        && s.bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+    if is_module_type {
+        return Ok(s.to_string());
+    }
+    key_type_exists(s).map_err(|err| format!("{}, or the 9 character name of a module type", err))
+}
+
+pub fn is_conflict_policy(s: &str) -> Result<ConflictPolicy, String> {
+    match s {
+        "replace" => Ok(ConflictPolicy::Replace),
+        "skip" => Ok(ConflictPolicy::Skip),
+        "merge" => Ok(ConflictPolicy::Merge),
+        "fail" => Ok(ConflictPolicy::Fail),
+        _ => Err("valid values are: replace | skip | merge | fail".to_string()),
+    }
+}
+
+pub fn is_expiry_mode(s: &str) -> Result<ExpiryMode, String> {
+    match s {
+        "relative" => Ok(ExpiryMode::Relative),
+        "absolute" => Ok(ExpiryMode::Absolute),
+        _ => Err("valid values are: relative | absolute".to_string()),
+    }
+}
+
+pub fn is_regex(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|err| err.to_string())
+}
+
+/// The default port of sentinels.
+const SENTINEL_DEFAULT_PORT: u16 = 26379;
+
+/// Parses the address of a sentinel: `host[:port]`, or a URL.
+pub fn is_sentinel_url(s: &str) -> Result<Url, String> {
+    let url = if s.contains("://") {
+        s.to_string()
+    } else {
+        format!("redis://{}", s)
+    };
+    let mut url = Url::parse(&url).map_err(|err| err.to_string())?;
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err("valid values are: host[:port] | <url>".to_string());
+    }
+    if url.port().is_none() {
+        let _ = url.set_port(Some(SENTINEL_DEFAULT_PORT));
+    }
+    Ok(url)
+}
+
+/// Collects the glob and regex patterns given on the command line.
+pub fn key_patterns(globs: Option<Vec<String>>, regexes: Option<Vec<Regex>>) -> Vec<KeyPattern> {
+    globs
+        .into_iter()
+        .flatten()
+        .map(KeyPattern::Glob)
+        .chain(regexes.into_iter().flatten().map(KeyPattern::Regex))
+        .collect()
+}
+
+/// Reads the TLS certificates given on the command line.
+pub fn tls_options(
+    ca: Option<&str>,
+    cert: Option<&str>,
+    key: Option<&str>,
+    insecure: bool,
+) -> anyhow::Result<TlsOptions> {
+    let read = |path: &str| fs::read(path).map_err(|err| anyhow!("{}: {}", path, err));
+    Ok(TlsOptions {
+        ca: ca.map(read).transpose()?,
+        client_cert: match (cert, key) {
+            (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+            _ => None,
+        },
+        insecure,
+    })
+}
+
+/// Collects the credentials given on the command line, so they aren't part of the URL.
+///
+/// The password is read from a file, from the terminal, or from the `REDISCLI_AUTH`
+/// environment variable, in that order. Like with redis-cli, the environment variable is only
+/// used if the URL has no password.
+pub fn credentials(
+    url: &Url,
+    username: Option<String>,
+    password_file: Option<&str>,
+    askpass: bool,
+) -> anyhow::Result<Credentials> {
+    let password = if let Some(path) = password_file {
+        let password = fs::read_to_string(path).map_err(|err| anyhow!("{}: {}", path, err))?;
+        // Only the line ending is trimmed: spaces may be part of the password.
+        let password = password.strip_suffix('\n').unwrap_or(&password);
+        Some(password.strip_suffix('\r').unwrap_or(password).to_string())
+    } else if askpass {
+        let prompt = match &username {
+            Some(username) => format!("Password for `{}`: ", username),
+            None => "Password: ".to_string(),
+        };
+        // The terminal is used even if stdin or stdout are redirected (e.g. to restore from
+        // stdin), and reading fails rather than echo the password if echo can't be turned off.
+        Some(
+            rpassword::prompt_password(prompt)
+                .map_err(|err| anyhow!("Can't read the password: {}", err))?,
+        )
+    } else if !has_password(url) {
+        env::var(REDISCLI_AUTH_ENV_VAR_KEY).ok()
+    } else {
+        None
+    };
+    Ok(Credentials { username, password })
+}
+
+/// Report the keys that failed: on stderr, and as JSON to the error report file, if any.
+///
+/// Fails if any key failed, so the process exits with a non-zero status.
+pub fn report_key_errors(errors: &[KeyError], path: Option<&str>) -> anyhow::Result<()> {
+    if let Some(path) = path {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, errors)?;
+        writeln!(writer)?;
+        writer.flush()?;
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    for error in errors.iter().take(MAX_REPORTED_ERRORS) {
+        eprintln!(
+            "db {}, `{}` ({}): {}",
+            error.db, error.key, error.key_type, error.reason
+        );
+    }
+    if errors.len() > MAX_REPORTED_ERRORS {
+        eprintln!("...");
+    }
+    Err(anyhow!("{} key(s) failed", errors.len()))
+}
@@ -1,3 +1,5 @@
-pub const REDIS_KEY_TYPE: [&str; 5] = ["string", "list", "set", "hash", "zset"];
+pub const REDIS_KEY_TYPE: [&str; 6] = ["string", "list", "set", "hash", "zset", "stream"];
 pub const REDIS_URL_ENV_VAR_KEY: &str = "REDIS_URL";
+/// The password, as read by `redis-cli`.
+pub const REDISCLI_AUTH_ENV_VAR_KEY: &str = "REDISCLI_AUTH";
 pub const REDIS_DEFAULT_URL: &str = "redis://localhost:6379";
@@ -2,7 +2,8 @@
 /// Private utility functions.
 /// Should not be used directly.
 ///
-use std::io::Write;
+use redis::{ErrorKind, FromRedisValue, RedisError, RedisResult};
+use std::{collections::HashMap, io::Write};
 use termcolor::{self, Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use url::Url;
 
@@ -16,7 +17,18 @@ pub fn print_red_error() -> Result<StandardStream, anyhow::Error> {
     Ok(stderr)
 }
 
+/// Returns the database selected by a URL, if any.
+///
+/// The database is the first segment of the path of TCP URLs (`redis://host:port/db`),
+/// and the `db` query parameter of Unix socket URLs (`redis+unix:///path/to.sock?db=N`),
+/// whose path is the socket.
 pub fn get_database_from_url(url: &Url) -> Option<u32> {
+    if matches!(url.scheme(), "unix" | "redis+unix") {
+        return url
+            .query_pairs()
+            .find(|(name, _)| name == "db")
+            .and_then(|(_, db)| db.parse::<u32>().ok());
+    }
     let db = url.path().split('/').collect::<Vec<&str>>();
 
     if db.is_empty() {
@@ -26,6 +38,47 @@ pub fn get_database_from_url(url: &Url) -> Option<u32> {
     db.get(1).cloned().and_then(|s| s.parse::<u32>().ok())
 }
 
+/// Returns the value of a field in the output of an INFO command, if present.
+pub fn get_info_field(info_cmd_output: &str, field: &str) -> Option<String> {
+    // Example of output for a redis-cli INFO server command:
+    // # Server
+    // redis_version:6.2.7
+    // redis_mode:standalone
+    info_cmd_output.lines().find_map(|line| {
+        line.strip_prefix(field)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .map(|value| value.trim().to_string())
+    })
+}
+
+/// Returns the value of a field in a map reply, such as the replies of the XINFO commands.
+pub fn get_map_field<T: FromRedisValue>(
+    map: &HashMap<String, redis::Value>,
+    field: &str,
+) -> RedisResult<T> {
+    let value = map.get(field).ok_or_else(|| {
+        RedisError::from((
+            ErrorKind::TypeError,
+            "Missing field in reply",
+            field.to_string(),
+        ))
+    })?;
+    redis::from_redis_value(value)
+}
+
+/// Returns the value of a field in a map reply, if the field is present and not nil.
+///
+/// Some fields are only reported by newer servers, or only when known.
+pub fn get_optional_map_field<T: FromRedisValue>(
+    map: &HashMap<String, redis::Value>,
+    field: &str,
+) -> RedisResult<Option<T>> {
+    match map.get(field) {
+        None | Some(redis::Value::Nil) => Ok(None),
+        Some(value) => redis::from_redis_value(value).map(Some),
+    }
+}
+
 /// Returns the indices of DBs with at least 1 key.
 pub fn get_all_non_empty_dbs(info_cmd_output: String) -> Vec<u32> {
     // Example of output for a redis-cli INFO keyspace command:
@@ -38,8 +91,7 @@ pub fn get_all_non_empty_dbs(info_cmd_output: String) -> Vec<u32> {
             let db_index = line
                 .split(':')
                 .next()
-                .map(|s| s.strip_prefix("db"))
-                .flatten()
+                .and_then(|s| s.strip_prefix("db"))
                 .unwrap(); // SAFE UNWRAP: Due to if statement, line must start with "db"
             db_indices.push(
                 db_index
@@ -1,32 +1,99 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::BuildHasher,
+    io::{Seek, SeekFrom, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::anyhow;
-use redis::Commands;
+use serde::{
+    ser::{SerializeMap, SerializeStruct},
+    Serialize, Serializer,
+};
 use url::Url;
 
 use crate::{
-    __private::{consts::REDIS_DEFAULT_URL, utils::get_database_from_url},
-    types::{RedisMeta, RedisValue},
+    __private::{
+        consts::{REDIS_DEFAULT_URL, REDIS_KEY_TYPE},
+        utils::{get_database_from_url, get_info_field, get_map_field, get_optional_map_field},
+    },
+    cluster::{self, key_slot, ClusterNode},
+    connection::{open_client, Credentials, SentinelOptions, SentinelServer, TlsOptions},
+    key_pattern::{matches_any, KeyPattern},
+    types::{
+        DumpHeader, DumpSummary, KeyError, RedisBytes, RedisEntries, RedisEntry, RedisPayload,
+        RedisStream, RedisStreamEntry, RedisStreamGroup, RedisStreamPending, RedisValue,
+        ServerInfo, FORMAT_VERSION,
+    },
+    Error,
 };
 
+/// The default number of keys read at once, see [`RedisDumpBuilder::with_batch_size`].
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+/// The default number of members read at once, see [`RedisDumpBuilder::with_chunk_size`].
+pub const DEFAULT_CHUNK_SIZE: usize = 10_000;
+/// The number of times a key is read before giving up, if its type keeps changing.
+const MAX_READ_ATTEMPTS: usize = 3;
+
+/// A filter on the keys to dump.
+///
+/// Filters can be combined: a key is dumped only if it passes all of them.
 #[derive(Default)]
 pub enum DumpFilter {
     #[default]
     None,
+    /// Dump only keys of the given types.
     Keys(Vec<String>),
+    /// Dump only keys matching at least one of the patterns.
+    Include(Vec<KeyPattern>),
+    /// Don't dump keys matching any of the patterns.
+    Exclude(Vec<KeyPattern>),
 }
 
 pub struct RedisDump {
     conn: redis::Connection,
     db: u32,
-    filter: DumpFilter,
+    filters: Vec<DumpFilter>,
     metadata: bool,
+    raw_payload: bool,
+    batch_size: usize,
+    chunk_size: usize,
+    keep_going: bool,
+    summary: DumpSummary,
+    errors: Vec<KeyError>,
+    cluster: Option<DumpCluster>,
+    /// Whether the server was found through sentinels.
+    sentinel: bool,
+}
+
+/// The nodes of the cluster being dumped.
+struct DumpCluster {
+    seed: Url,
+    tls: TlsOptions,
+    nodes: Vec<ClusterNode>,
+    /// The node the connection is connected to, if any.
+    current: Option<usize>,
 }
 
 pub struct RedisDumpBuilder {
     url: Url,
-    filter: DumpFilter,
+    credentials: Credentials,
+    tls: TlsOptions,
+    cluster: bool,
+    sentinel: Option<SentinelOptions>,
+    replicas: bool,
+    filters: Vec<DumpFilter>,
     metadata: bool,
+    raw_payload: bool,
+    batch_size: usize,
+    chunk_size: usize,
+    keep_going: bool,
+}
+
+impl Default for RedisDumpBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RedisDumpBuilder {
@@ -34,34 +101,130 @@ impl RedisDumpBuilder {
         Self {
             // SAFE UNWRAP: The default URL is a valid URL.
             url: Url::parse(REDIS_DEFAULT_URL).unwrap(),
-            filter: DumpFilter::None,
+            credentials: Credentials::default(),
+            tls: TlsOptions::default(),
+            cluster: false,
+            sentinel: None,
+            replicas: false,
+            filters: Vec::new(),
             metadata: true,
+            raw_payload: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            keep_going: false,
         }
     }
     pub fn with_url(mut self, url: Url) -> Self {
         self.url = url;
         self
     }
+    /// Authenticate with the given credentials, rather than with the ones of the URL.
+    ///
+    /// They are also used for the nodes of a cluster, and for servers found through Sentinel.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+    /// Set the TLS options, for a `rediss://` URL.
+    pub fn with_tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = tls;
+        self
+    }
+    /// Dump a whole Redis Cluster, whose topology is discovered from the node at the URL.
+    ///
+    /// Every primary is scanned in turn, and each entry is tagged with its hash slot.
+    pub fn with_cluster(mut self, cluster: bool) -> Self {
+        self.cluster = cluster;
+        self
+    }
+    /// Dump the master monitored by the given sentinels, rather than the server at the URL.
+    ///
+    /// The sentinels are only asked when connecting. If the server fails over during the dump,
+    /// the dump fails: a scan can't go on on another server, and must be run again.
+    pub fn with_sentinel(mut self, sentinel: SentinelOptions) -> Self {
+        self.sentinel = Some(sentinel);
+        self
+    }
+    /// Read from replicas (if there are any) rather than from primaries, so they aren't loaded
+    /// by the dump: from a replica of each shard of a cluster, or from a replica of the master
+    /// monitored by the sentinels.
+    pub fn with_replicas(mut self, replicas: bool) -> Self {
+        self.replicas = replicas;
+        self
+    }
+    /// Add a filter on the keys to dump. A key is dumped only if it passes all filters.
     pub fn with_filter(mut self, filter: DumpFilter) -> Self {
-        self.filter = filter;
+        self.filters.push(filter);
         self
     }
     pub fn with_metadata(mut self, metadata: bool) -> Self {
         self.metadata = metadata;
         self
     }
-    pub fn connect(self) -> anyhow::Result<RedisDump> {
-        let conn = redis::Client::open(self.url.as_str())?.get_connection()?;
-        let db = if let Some(db) = get_database_from_url(&self.url) {
-            db
+    /// Dump keys as their `DUMP` payload, instead of their (per-type) value.
+    pub fn with_raw_payload(mut self, raw_payload: bool) -> Self {
+        self.raw_payload = raw_payload;
+        self
+    }
+    /// Read keys in batches of the given size: each batch is scanned, typed and read
+    /// with one round trip each. Larger batches are faster, but use more memory.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+    /// Read hashes, sets, sorted sets and lists with more members than the given size
+    /// in chunks of that size (with `HSCAN`, `SSCAN`, `ZSCAN` and `LRANGE`), rather than
+    /// all at once, so the server isn't blocked by a single huge reply.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+    /// Skip keys that can't be read instead of failing, and keep them in
+    /// [`RedisDump::errors`]. Errors of the connection itself still fail the dump.
+    pub fn with_keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+    pub fn connect(mut self) -> Result<RedisDump, Error> {
+        self.url = self.credentials.apply(&self.url)?;
+        if self.cluster && self.sentinel.is_some() {
+            return Err(Error::Connection(redis::RedisError::from((
+                redis::ErrorKind::InvalidClientConfig,
+                "A cluster isn't monitored by Sentinel",
+            ))));
+        }
+        let sentinel = self.sentinel.map(|sentinel| {
+            SentinelServer::new(self.url.clone(), self.tls.clone(), sentinel, self.replicas)
+        });
+        let mut conn = match &sentinel {
+            Some(sentinel) => sentinel.connect()?,
+            None => open_client(&self.url, &self.tls)?.get_connection()?,
+        };
+        let db = get_database_from_url(&self.url).unwrap_or_default();
+        let cluster = if self.cluster {
+            let seed_host = self.url.host_str().unwrap_or_default();
+            Some(DumpCluster {
+                nodes: cluster::discover(&mut conn, seed_host, self.replicas)?,
+                seed: self.url,
+                tls: self.tls,
+                current: None,
+            })
         } else {
-            0
+            None
         };
         Ok(RedisDump {
             conn,
             db,
-            filter: self.filter,
+            filters: self.filters,
             metadata: self.metadata,
+            raw_payload: self.raw_payload,
+            batch_size: self.batch_size,
+            chunk_size: self.chunk_size,
+            keep_going: self.keep_going,
+            summary: DumpSummary::default(),
+            errors: Vec::new(),
+            cluster,
+            sentinel: sentinel.is_some(),
         })
     }
 }
@@ -86,75 +249,962 @@ impl RedisDump {
         &mut self.conn
     }
 
+    /// Get the active database.
+    ///
+    pub fn db(&self) -> u32 {
+        self.db
+    }
+
     /// Select the active database.
     ///
     /// This is a no-op if the database is already selected.
-    pub fn select_db(&mut self, db: u32) -> Result<(), anyhow::Error> {
+    pub fn select_db(&mut self, db: u32) -> Result<(), Error> {
         if self.db == db {
             return Ok(());
         }
-        redis::cmd("SELECT").arg(db).query(&mut self.conn)?;
+        redis::cmd("SELECT").arg(db).query::<()>(&mut self.conn)?;
         self.db = db;
         Ok(())
     }
 
+    /// Keys that changed while they were read, since the connection (or the start of the
+    /// last [`RedisDump::write_json`]).
+    ///
+    pub fn summary(&self) -> DumpSummary {
+        self.summary
+    }
+
+    /// Keys that couldn't be read, when keeping going (see [`RedisDumpBuilder::with_keep_going`]),
+    /// since the connection (or the start of the last [`RedisDump::write_json`]).
+    ///
+    pub fn errors(&self) -> &[KeyError] {
+        &self.errors
+    }
+
+    /// Build the header of a dump taken from this server.
+    ///
+    pub fn header(&mut self) -> Result<DumpHeader, Error> {
+        let info: String = redis::cmd("INFO").arg("server").query(&mut self.conn)?;
+        Ok(DumpHeader {
+            format_version: FORMAT_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            server: ServerInfo {
+                redis_version: get_info_field(&info, "redis_version"),
+                redis_mode: get_info_field(&info, "redis_mode"),
+                os: get_info_field(&info, "os"),
+            },
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            summary: None,
+        })
+    }
+
     /// Dump all keys in the active database.
     ///
-    /// If a filter is set, only keys matching the filter will be dumped.
+    /// If filters are set, only keys passing all the filters will be dumped.
     ///
     /// Otherwise, all keys will be dumped.
-    pub fn entries(&mut self) -> Result<HashMap<String, RedisValue>, anyhow::Error> {
-        let mut entries = HashMap::<String, RedisValue>::new();
-        let keys = self.conn.scan::<String>()?.collect::<Vec<_>>();
-
-        for key in keys.iter() {
-            let key_type: String = redis::cmd("TYPE").arg(key).query(&mut self.conn)?;
-            // If the user specified which keys to dump, and this key is not in the list, skip it.
-            if let DumpFilter::Keys(ref key_types) = self.filter {
-                if !key_types.contains(&key_type) {
-                    continue;
+    /// All entries are kept in memory: prefer [`RedisDump::iter`] for large databases.
+    pub fn entries(&mut self) -> Result<RedisEntries, Error> {
+        self.iter().collect()
+    }
+
+    /// Iterate over the keys of the active database, reading them one at a time.
+    ///
+    /// In a cluster (see [`RedisDumpBuilder::with_cluster`]), the nodes are scanned in turn.
+    ///
+    /// Keys are scanned and read in batches (see [`RedisDumpBuilder::with_batch_size`]),
+    /// so memory use doesn't grow with the size of the values of the database.
+    /// `SCAN` may return a key more than once (when the database is rehashed meanwhile), but
+    /// each key is returned once: a 16-byte fingerprint of each key returned is kept, so memory
+    /// still grows with the number of keys, though not with the size of their names.
+    /// The iteration stops after the first error.
+    pub fn iter(&mut self) -> DumpIter<'_> {
+        DumpIter {
+            // A single glob to include is matched by the server, so only matching keys are scanned.
+            pattern: self.scan_pattern(),
+            rd: self,
+            node: 0,
+            cursor: Some(0),
+            seen: SeenKeys::default(),
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    /// Write a dump of the given databases as JSON, reading and writing one key at a time.
+    ///
+    /// The header is written first, so the dump can be read back incrementally. The summary
+    /// of keys that changed during the dump is only known at the end, so it isn't written
+    /// (see [`RedisDump::write_json_seekable`]).
+    pub fn write_json<W: Write>(
+        &mut self,
+        dbs: &[u32],
+        writer: W,
+        pretty: bool,
+    ) -> Result<(), Error> {
+        self.write_dump(dbs, writer, pretty, None).map(drop)
+    }
+
+    /// Write a dump like [`RedisDump::write_json`], along with the summary of keys that
+    /// changed during the dump in its header.
+    ///
+    /// Room is left for the summary in the header, and it is written there once every key
+    /// is read, so the writer must be seekable, like a file.
+    pub fn write_json_seekable<W: Write + Seek>(
+        &mut self,
+        dbs: &[u32],
+        mut writer: W,
+        pretty: bool,
+    ) -> Result<(), Error> {
+        let start = writer.stream_position()?;
+        let slot = SummarySlot::default();
+        let mut writer = self.write_dump(dbs, writer, pretty, Some(&slot))?;
+        let end = writer.stream_position()?;
+        writer.seek(SeekFrom::Start(start + slot.offset.get()))?;
+        writer.write_all(summary_json(&self.summary).as_bytes())?;
+        writer.seek(SeekFrom::Start(end))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write a dump as JSON, leaving room for the summary in the header if a slot is given.
+    ///
+    /// Returns the writer, once flushed.
+    fn write_dump<W: Write>(
+        &mut self,
+        dbs: &[u32],
+        writer: W,
+        pretty: bool,
+        slot: Option<&SummarySlot>,
+    ) -> Result<W, Error> {
+        self.summary = DumpSummary::default();
+        self.errors.clear();
+        let unused = Cell::new(0);
+        let mut writer = CountingWriter {
+            inner: writer,
+            written: slot.map_or(&unused, |slot| &slot.written),
+        };
+        let dump = StreamedDump {
+            header: self.header()?,
+            summary: slot,
+            databases: StreamedDatabases {
+                rd: RefCell::new(self),
+                dbs,
+                error: RefCell::new(None),
+            },
+        };
+        let result = if pretty {
+            serde_json::to_writer_pretty(&mut writer, &dump)
+        } else {
+            serde_json::to_writer(&mut writer, &dump)
+        };
+        // Errors from the server are reported as they are, rather than as serialization errors.
+        if let Some(err) = dump.databases.error.into_inner() {
+            return Err(err);
+        }
+        result?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(writer.inner)
+    }
+
+    /// The number of nodes to scan: the nodes of the cluster, or the single server.
+    fn node_count(&self) -> usize {
+        self.cluster
+            .as_ref()
+            .map_or(1, |cluster| cluster.nodes.len())
+    }
+
+    /// Connect to the given node of the cluster, unless already connected to it.
+    ///
+    /// This is a no-op outside of a cluster.
+    fn use_node(&mut self, node: usize) -> Result<(), Error> {
+        let Some(cluster) = &mut self.cluster else {
+            return Ok(());
+        };
+        if cluster.current == Some(node) {
+            return Ok(());
+        }
+        let target = &cluster.nodes[node];
+        let mut conn = open_client(&target.url(&cluster.seed)?, &cluster.tls)?.get_connection()?;
+        if target.replica {
+            redis::cmd("READONLY").query::<()>(&mut conn)?;
+        }
+        self.conn = conn;
+        cluster.current = Some(node);
+        Ok(())
+    }
+
+    /// Scan a batch of keys, starting at the given cursor.
+    ///
+    /// Returns the cursor to continue from (0 once the scan is complete), and the keys.
+    fn scan_batch(
+        &mut self,
+        cursor: u64,
+        pattern: Option<&str>,
+    ) -> Result<(u64, Vec<RedisBytes>), Error> {
+        let mut cmd = redis::cmd("SCAN");
+        cmd.arg(cursor);
+        if let Some(pattern) = pattern {
+            cmd.arg("MATCH").arg(pattern);
+        }
+        Ok(cmd
+            .arg("COUNT")
+            .arg(self.batch_size)
+            .query(&mut self.conn)?)
+    }
+
+    /// Read a batch of keys, leaving out the ones that don't pass the filters
+    /// (and, in a cluster, the ones whose slot isn't served by the current node).
+    ///
+    /// Keys deleted before they are read are skipped, and keys whose type changes while
+    /// they are read are read again (see [`RedisDump::summary`]).
+    pub(crate) fn read_batch(
+        &mut self,
+        keys: Vec<RedisBytes>,
+    ) -> Result<Vec<(RedisBytes, RedisEntry)>, Error> {
+        let mut keys = keys
+            .into_iter()
+            .filter(|key| self.matches_key(key) && self.serves_key(key))
+            .collect::<Vec<_>>();
+        let mut entries = Vec::with_capacity(keys.len());
+        let mut retyped = Vec::new();
+        for attempt in 1..=MAX_READ_ATTEMPTS {
+            retyped = self.read_keys(keys, &mut entries)?;
+            if retyped.is_empty() {
+                return Ok(entries);
+            }
+            // Keys left after the last attempt fail instead of being read again.
+            if attempt < MAX_READ_ATTEMPTS {
+                self.summary.retyped += retyped.len();
+            }
+            keys = retyped.iter().map(|(key, _)| key.clone()).collect();
+        }
+        for (key, key_type) in retyped {
+            let err = Error::protocol("The type of the key kept changing while it was read");
+            self.key_failed(key, key_type, err)?;
+        }
+        Ok(entries)
+    }
+
+    /// Read keys, and return the ones whose type changed while they were read, with the type
+    /// they had before.
+    ///
+    /// The types of the keys are read with a single pipeline, then the sizes of collections,
+    /// then their values and TTLs. Streams need several dependent commands, and collections
+    /// larger than the chunk size are read in chunks, so they are both read after.
+    fn read_keys(
+        &mut self,
+        keys: Vec<RedisBytes>,
+        entries: &mut Vec<(RedisBytes, RedisEntry)>,
+    ) -> Result<Vec<(RedisBytes, String)>, Error> {
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.cmd("TYPE").arg(key);
+        }
+        let key_types: Vec<String> = pipe.query(&mut self.conn)?;
+        let mut typed = Vec::with_capacity(keys.len());
+        for (key, key_type) in keys.into_iter().zip(key_types) {
+            if key_type == "none" {
+                self.summary.vanished += 1;
+            } else if !self.matches_type(&key_type) {
+                continue;
+            } else if self.raw_payload || REDIS_KEY_TYPE.contains(&key_type.as_str()) {
+                typed.push((key, key_type));
+            } else {
+                let err = Error::UnsupportedType {
+                    key: key.clone(),
+                    key_type: key_type.clone(),
+                };
+                self.key_failed(key, key_type, err)?;
+            }
+        }
+
+        let mut retyped = Vec::new();
+        let mut pipe = redis::pipe();
+        let mut count = 0;
+        for (key, key_type) in &typed {
+            if let (false, Some(len)) = (self.raw_payload, len_command(key_type)) {
+                pipe.cmd(len).arg(key);
+                count += 1;
+            }
+        }
+        let mut lens = self.query_each(&pipe, count)?.into_iter();
+        let mut keys = Vec::with_capacity(typed.len());
+        for (key, key_type) in typed {
+            let mut chunked = false;
+            if !self.raw_payload && len_command(&key_type).is_some() {
+                match next_result(&mut lens)? {
+                    Ok(len) => chunked = redis::from_redis_value::<usize>(&len)? > self.chunk_size,
+                    Err(err) if err.code() == Some("WRONGTYPE") => {
+                        retyped.push((key, key_type));
+                        continue;
+                    }
+                    Err(err) => {
+                        self.key_failed(key, key_type, err.into())?;
+                        continue;
+                    }
                 }
             }
+            keys.push((key, key_type, chunked));
+        }
+
+        let mut pipe = redis::pipe();
+        let mut count = 0;
+        if self.metadata {
+            // The clock of the server, to turn TTLs into absolute expiry times.
+            pipe.cmd("TIME");
+            count += 1;
+        }
+        for (key, key_type, chunked) in &keys {
+            if self.raw_payload {
+                pipe.cmd("DUMP").arg(key);
+            } else if !chunked {
+                match key_type.as_str() {
+                    "string" => pipe.cmd("GET").arg(key),
+                    "list" => pipe.cmd("LRANGE").arg(key).arg(0).arg(-1),
+                    "set" => pipe.cmd("SMEMBERS").arg(key),
+                    "hash" => pipe.cmd("HGETALL").arg(key),
+                    "zset" => pipe.cmd("ZRANGE").arg(key).arg(0).arg(-1).arg("WITHSCORES"),
+                    // Streams are read after.
+                    _ => &mut pipe,
+                };
+            }
+            if self.raw_payload || !chunked && key_type != "stream" {
+                count += 1;
+            }
+            if self.metadata {
+                pipe.cmd("PTTL").arg(key);
+                count += 1;
+            }
+        }
+        let mut replies = self.query_each(&pipe, count)?.into_iter();
+        let now_ms = if self.metadata {
+            let (secs, micros): (u64, u64) = next_reply(&mut replies)?;
+            secs * 1000 + micros / 1000
+        } else {
+            0
+        };
+
+        for (key, key_type, chunked) in keys {
+            let reply = if self.raw_payload || !chunked && key_type != "stream" {
+                Some(next_result(&mut replies)?)
+            } else {
+                None
+            };
+            let pttl = if self.metadata {
+                Some(next_result(&mut replies)?)
+            } else {
+                None
+            };
+            match self.read_key(&key, &key_type, chunked, reply, pttl, now_ms) {
+                Ok(Read::Entry(entry)) => entries.push((key, entry)),
+                Ok(Read::Vanished) => self.summary.vanished += 1,
+                Ok(Read::Retyped) => retyped.push((key, key_type)),
+                Err(err) if err.is_connection_error() => return Err(err),
+                Err(err) => self.key_failed(key, key_type, err)?,
+            }
+        }
+        Ok(retyped)
+    }
 
-            let value = match key_type.as_str() {
-                "string" => {
-                    let value: String = self.conn.get(key)?;
-                    RedisValue::String(value)
+    /// Build the entry of a key, from the replies of the value pipeline (if it was read with it)
+    /// and further commands.
+    fn read_key(
+        &mut self,
+        key: &RedisBytes,
+        key_type: &str,
+        chunked: bool,
+        reply: Option<redis::RedisResult<redis::Value>>,
+        pttl: Option<redis::RedisResult<redis::Value>>,
+        now_ms: u64,
+    ) -> Result<Read, Error> {
+        let pttl: Option<i64> = pttl
+            .map(|pttl| redis::from_redis_value(&pttl?))
+            .transpose()?;
+        // A PTTL of -2 means that the key doesn't exist anymore.
+        if pttl == Some(-2) {
+            return Ok(Read::Vanished);
+        }
+        let value = match reply {
+            Some(Err(err)) if err.code() == Some("WRONGTYPE") => return Ok(Read::Retyped),
+            Some(reply) => {
+                let reply = reply?;
+                if reply == redis::Value::Nil {
+                    return Ok(Read::Vanished);
                 }
-                "list" => {
-                    let value: Vec<String> = self.conn.lrange(key, 0, -1)?;
-                    RedisValue::List(value)
+                if self.raw_payload {
+                    RedisValue::Raw(RedisPayload {
+                        key_type: key_type.to_string(),
+                        payload: redis::from_redis_value(&reply)?,
+                    })
+                } else {
+                    match key_type {
+                        "string" => RedisValue::String(redis::from_redis_value(&reply)?),
+                        "list" => RedisValue::List(redis::from_redis_value(&reply)?),
+                        "set" => RedisValue::Set(redis::from_redis_value(&reply)?),
+                        "hash" => RedisValue::Hash(redis::from_redis_value(&reply)?),
+                        _ => RedisValue::ZSet(redis::from_redis_value(&reply)?),
+                    }
+                }
+            }
+            None => {
+                let value = if chunked {
+                    self.chunked_value(key, key_type)
+                } else {
+                    self.stream(key).map(RedisValue::Stream)
+                };
+                match value {
+                    Ok(value) => value,
+                    // The commands may have failed because the key changed: find out how.
+                    Err(err) => {
+                        let current: String = redis::cmd("TYPE").arg(key).query(&mut self.conn)?;
+                        if current == "none" {
+                            return Ok(Read::Vanished);
+                        } else if current != key_type {
+                            return Ok(Read::Retyped);
+                        }
+                        return Err(err);
+                    }
                 }
-                "set" => {
-                    let value: HashSet<String> = self.conn.smembers(key)?;
-                    RedisValue::Set(value)
+            }
+        };
+        // Redis deletes collections once they are empty.
+        if is_empty_collection(&value) {
+            return Ok(Read::Vanished);
+        }
+        Ok(Read::Entry(RedisEntry {
+            value,
+            pttl,
+            expire_at: pttl
+                .filter(|pttl| *pttl > 0)
+                .map(|pttl| now_ms + pttl as u64),
+            slot: self.cluster.as_ref().map(|_| key_slot(key)),
+        }))
+    }
+
+    /// Keep a key that couldn't be read when keeping going, or fail.
+    fn key_failed(&mut self, key: RedisBytes, key_type: String, err: Error) -> Result<(), Error> {
+        if !self.keep_going {
+            return Err(err.with_key(&key));
+        }
+        self.errors.push(KeyError {
+            key,
+            db: self.db,
+            key_type,
+            reason: err.reason(),
+        });
+        Ok(())
+    }
+
+    /// Send a pipeline, and read each of its `count` replies, errors included.
+    ///
+    /// Unlike `Pipeline::query`, a failing command (e.g. on a key whose type changed)
+    /// doesn't fail the whole pipeline.
+    fn query_each(
+        &mut self,
+        pipe: &redis::Pipeline,
+        count: usize,
+    ) -> Result<Vec<redis::RedisResult<redis::Value>>, Error> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        self.conn.send_packed_command(&pipe.get_packed_pipeline())?;
+        let mut replies = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self
+                .conn
+                .recv_response()
+                .and_then(redis::Value::extract_error)
+            {
+                // The connection is lost, so there are no replies left to read.
+                Err(err) if err.is_io_error() => return Err(err.into()),
+                reply => replies.push(reply),
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Read a hash, set, sorted set or list in chunks.
+    ///
+    /// Members added or removed while the key is read may or may not be dumped.
+    fn chunked_value(&mut self, key: &RedisBytes, key_type: &str) -> Result<RedisValue, Error> {
+        Ok(match key_type {
+            "hash" => RedisValue::Hash(self.scan_collection("HSCAN", key)?.collect()),
+            "set" => RedisValue::Set(self.scan_collection("SSCAN", key)?.collect()),
+            "zset" => {
+                // ZSCAN may return a member more than once, and in no particular order.
+                let scores: HashMap<RedisBytes, f64> =
+                    self.scan_collection("ZSCAN", key)?.collect();
+                let mut zset = scores.into_iter().collect::<Vec<_>>();
+                zset.sort_by(|(a, a_score), (b, b_score)| {
+                    a_score.total_cmp(b_score).then_with(|| a.cmp(b))
+                });
+                RedisValue::ZSet(zset)
+            }
+            _ => {
+                let mut list = Vec::new();
+                loop {
+                    let start = list.len();
+                    let chunk: Vec<RedisBytes> = redis::cmd("LRANGE")
+                        .arg(key)
+                        .arg(start)
+                        .arg(start + self.chunk_size - 1)
+                        .query(&mut self.conn)?;
+                    let done = chunk.len() < self.chunk_size;
+                    list.extend(chunk);
+                    if done {
+                        break RedisValue::List(list);
+                    }
                 }
-                "hash" => {
-                    let value: HashMap<String, String> = self.conn.hgetall(key)?;
-                    RedisValue::Hash(value)
+            }
+        })
+    }
+
+    /// Read all the members of a collection with the given `*SCAN` command.
+    fn scan_collection<T: redis::FromRedisValue>(
+        &mut self,
+        scan: &str,
+        key: &RedisBytes,
+    ) -> Result<impl Iterator<Item = T>, Error> {
+        let mut members = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, chunk): (u64, Vec<T>) = redis::cmd(scan)
+                .arg(key)
+                .arg(cursor)
+                .arg("COUNT")
+                .arg(self.chunk_size)
+                .query(&mut self.conn)?;
+            members.extend(chunk);
+            if next == 0 {
+                return Ok(members.into_iter());
+            }
+            cursor = next;
+        }
+    }
+
+    /// The glob pattern keys can be scanned with, if the filters allow it.
+    fn scan_pattern(&self) -> Option<String> {
+        self.filters.iter().find_map(|filter| match filter {
+            DumpFilter::Include(patterns) => match patterns.as_slice() {
+                [KeyPattern::Glob(glob)] => Some(glob.clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    /// Whether the key name passes the filters.
+    fn matches_key(&self, key: &[u8]) -> bool {
+        self.filters.iter().all(|filter| match filter {
+            DumpFilter::Include(patterns) => matches_any(patterns, key),
+            DumpFilter::Exclude(patterns) => !matches_any(patterns, key),
+            DumpFilter::None | DumpFilter::Keys(_) => true,
+        })
+    }
+
+    /// Whether the current node serves the slot of the key, which is always the case
+    /// outside of a cluster.
+    ///
+    /// A node may still hold keys of slots that are being migrated to another one.
+    fn serves_key(&self, key: &[u8]) -> bool {
+        match &self.cluster {
+            Some(DumpCluster {
+                nodes,
+                current: Some(node),
+                ..
+            }) => nodes[*node].serves(key_slot(key)),
+            _ => true,
+        }
+    }
+
+    /// Whether the key type passes the filters.
+    fn matches_type(&self, key_type: &str) -> bool {
+        self.filters.iter().all(|filter| match filter {
+            DumpFilter::Keys(key_types) => key_types.iter().any(|t| t == key_type),
+            _ => true,
+        })
+    }
+
+    /// Dump a stream, along with its consumer groups and their pending entries.
+    fn stream(&mut self, key: &RedisBytes) -> Result<RedisStream, Error> {
+        // Each entry is a nested `[id, [field, value, ...]]` reply, which can't be parsed
+        // as a vector of tuples directly.
+        let entries: Vec<redis::Value> = redis::cmd("XRANGE")
+            .arg(key)
+            .arg("-")
+            .arg("+")
+            .query(&mut self.conn)?;
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                let (id, fields) = redis::from_redis_value(entry)?;
+                Ok(RedisStreamEntry { id, fields })
+            })
+            .collect::<Result<Vec<_>, redis::RedisError>>()?;
+
+        let info: HashMap<String, redis::Value> = redis::cmd("XINFO")
+            .arg("STREAM")
+            .arg(key)
+            .query(&mut self.conn)?;
+        let last_id = get_map_field(&info, "last-generated-id")?;
+        // Only reported by Redis 7 or later.
+        let entries_added = get_optional_map_field(&info, "entries-added")?;
+        let max_deleted_entry_id = get_optional_map_field(&info, "max-deleted-entry-id")?;
+
+        let groups_info: Vec<HashMap<String, redis::Value>> = redis::cmd("XINFO")
+            .arg("GROUPS")
+            .arg(key)
+            .query(&mut self.conn)?;
+        let mut groups = Vec::with_capacity(groups_info.len());
+        for group_info in groups_info {
+            let name: RedisBytes = get_map_field(&group_info, "name")?;
+            let pending_count: u64 = get_map_field(&group_info, "pending")?;
+
+            let consumers_info: Vec<HashMap<String, redis::Value>> = redis::cmd("XINFO")
+                .arg("CONSUMERS")
+                .arg(key)
+                .arg(&name)
+                .query(&mut self.conn)?;
+            let consumers = consumers_info
+                .iter()
+                .map(|consumer_info| get_map_field(consumer_info, "name"))
+                .collect::<Result<Vec<RedisBytes>, _>>()?;
+
+            let pending = if pending_count > 0 {
+                let pending: Vec<redis::Value> = redis::cmd("XPENDING")
+                    .arg(key)
+                    .arg(&name)
+                    .arg("-")
+                    .arg("+")
+                    .arg(pending_count)
+                    .query(&mut self.conn)?;
+                pending
+                    .iter()
+                    .map(|pending| {
+                        let (id, consumer, idle, delivery_count) =
+                            redis::from_redis_value(pending)?;
+                        Ok(RedisStreamPending {
+                            id,
+                            consumer,
+                            idle,
+                            delivery_count,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, redis::RedisError>>()?
+            } else {
+                Vec::new()
+            };
+
+            groups.push(RedisStreamGroup {
+                name,
+                last_delivered_id: get_map_field(&group_info, "last-delivered-id")?,
+                entries_read: get_optional_map_field(&group_info, "entries-read")?,
+                consumers,
+                pending,
+            });
+        }
+
+        Ok(RedisStream {
+            entries,
+            last_id,
+            entries_added,
+            max_deleted_entry_id,
+            groups,
+        })
+    }
+}
+
+/// An iterator over the entries of a database, see [`RedisDump::iter`].
+pub struct DumpIter<'a> {
+    rd: &'a mut RedisDump,
+    pattern: Option<String>,
+    /// The node being scanned, in a cluster.
+    node: usize,
+    /// The cursor of the next batch, or `None` once the scan is complete.
+    cursor: Option<u64>,
+    /// The keys read so far, which aren't read again.
+    seen: SeenKeys,
+    /// The entries of the current batch that are left to return.
+    entries: std::vec::IntoIter<(RedisBytes, RedisEntry)>,
+}
+
+impl Iterator for DumpIter<'_> {
+    type Item = Result<(RedisBytes, RedisEntry), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            let cursor = self.cursor?;
+            let batch = self
+                .rd
+                .use_node(self.node)
+                .and_then(|_| self.rd.scan_batch(cursor, self.pattern.as_deref()))
+                .and_then(|(next, keys)| {
+                    let keys = self.seen.unseen(keys);
+                    Ok((next, keys.clone(), self.rd.read_batch(keys)?))
+                });
+            match batch {
+                Ok((next, keys, entries)) => {
+                    self.seen.extend(keys);
+                    self.cursor = if next != 0 {
+                        Some(next)
+                    } else {
+                        // The scan of this node is complete: move on to the next one, if any.
+                        self.node += 1;
+                        (self.node < self.rd.node_count()).then_some(0)
+                    };
+                    self.entries = entries.into_iter();
                 }
-                "zset" => {
-                    let value: Vec<(String, f32)> = self.conn.zrange_withscores(key, 0, -1)?;
-                    RedisValue::ZSet(value)
+                // Cursors are only valid on the server they come from, so a scan can't go on
+                // on the new server after a failover.
+                Err(err) if self.rd.sentinel => {
+                    self.cursor = None;
+                    return Some(Err(err.into_failover()));
                 }
-                _ => {
-                    return Err(anyhow!("{}: Unsupported type", key));
+                Err(err) => {
+                    self.cursor = None;
+                    return Some(Err(err));
                 }
-            };
-            entries.insert(
-                key.to_string(),
-                if self.metadata {
-                    RedisValue::Meta(RedisMeta {
-                        db: self.db,
-                        r#type: key_type,
-                        ttl: self.conn.ttl(key)?,
-                        data: Box::new(value),
-                    })
-                } else {
-                    value
+            }
+        }
+    }
+}
+
+/// The keys returned by a scan so far.
+///
+/// SCAN may return a key more than once (when the database is rehashed meanwhile), so keys
+/// are only read the first time they are returned: a dump never holds a key twice. Only a
+/// 128-bit fingerprint of each key is kept, so memory doesn't grow with the size of key names.
+pub(crate) struct SeenKeys {
+    /// Two independently seeded hashers, whose hashes make up the fingerprint.
+    hashers: [RandomState; 2],
+    fingerprints: HashSet<u128>,
+}
+
+impl Default for SeenKeys {
+    fn default() -> Self {
+        Self {
+            hashers: [RandomState::new(), RandomState::new()],
+            fingerprints: HashSet::new(),
+        }
+    }
+}
+
+impl SeenKeys {
+    /// The keys of a scanned page that weren't seen yet, each once.
+    pub(crate) fn unseen(&self, mut keys: Vec<RedisBytes>) -> Vec<RedisBytes> {
+        let mut page = HashSet::with_capacity(keys.len());
+        keys.retain(|key| {
+            let fingerprint = self.fingerprint(key);
+            !self.fingerprints.contains(&fingerprint) && page.insert(fingerprint)
+        });
+        keys
+    }
+
+    /// Remember the keys of a page once it is read.
+    pub(crate) fn extend(&mut self, keys: Vec<RedisBytes>) {
+        for key in &keys {
+            self.fingerprints.insert(self.fingerprint(key));
+        }
+    }
+
+    fn fingerprint(&self, key: &RedisBytes) -> u128 {
+        let [high, low] = &self.hashers;
+        (u128::from(high.hash_one(key)) << 64) | u128::from(low.hash_one(key))
+    }
+}
+
+/// The command returning the number of members of a collection, if the type is one.
+fn len_command(key_type: &str) -> Option<&'static str> {
+    match key_type {
+        "hash" => Some("HLEN"),
+        "set" => Some("SCARD"),
+        "zset" => Some("ZCARD"),
+        "list" => Some("LLEN"),
+        _ => None,
+    }
+}
+
+/// Parse the next reply of a pipeline read with [`RedisDump::query_each`].
+fn next_reply<T: redis::FromRedisValue>(
+    replies: &mut impl Iterator<Item = redis::RedisResult<redis::Value>>,
+) -> Result<T, Error> {
+    let reply = next_result(replies)??;
+    Ok(redis::from_redis_value(&reply)?)
+}
+
+/// Take the next reply of a pipeline read with [`RedisDump::query_each`], error or not.
+fn next_result(
+    replies: &mut impl Iterator<Item = redis::RedisResult<redis::Value>>,
+) -> Result<redis::RedisResult<redis::Value>, Error> {
+    replies
+        .next()
+        .ok_or_else(|| Error::protocol("Missing reply in pipeline"))
+}
+
+/// What became of a key that was read.
+enum Read {
+    Entry(RedisEntry),
+    /// The key was deleted before it was read.
+    Vanished,
+    /// The type of the key changed while it was read.
+    Retyped,
+}
+
+/// Whether the value is a collection without members, which Redis never keeps (streams aside).
+fn is_empty_collection(value: &RedisValue) -> bool {
+    match value {
+        RedisValue::List(list) => list.is_empty(),
+        RedisValue::Set(set) => set.is_empty(),
+        RedisValue::Hash(hash) => hash.is_empty(),
+        RedisValue::ZSet(zset) => zset.is_empty(),
+        _ => false,
+    }
+}
+
+/// A dump file, whose entries are read from the server while it is serialized.
+///
+/// This mirrors the layout of [`crate::types::RedisDumpFile`].
+struct StreamedDump<'a> {
+    header: DumpHeader,
+    /// The room left for the summary in the header, if any.
+    summary: Option<&'a SummarySlot>,
+    databases: StreamedDatabases<'a>,
+}
+
+impl Serialize for StreamedDump<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut dump = serializer.serialize_struct("RedisDumpFile", 2)?;
+        dump.serialize_field(
+            "header",
+            &StreamedHeader {
+                header: &self.header,
+                summary: self.summary,
+            },
+        )?;
+        dump.serialize_field("databases", &self.databases)?;
+        dump.end()
+    }
+}
+
+/// The header of a streamed dump, with room for its summary.
+#[derive(Serialize)]
+struct StreamedHeader<'a> {
+    #[serde(flatten)]
+    header: &'a DumpHeader,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<&'a SummarySlot>,
+}
+
+/// The room left for the summary in the header of a dump, written once the summary is known.
+#[derive(Default)]
+struct SummarySlot {
+    /// The number of bytes written so far, see [`CountingWriter`].
+    written: Cell<u64>,
+    /// Where the summary is, from the start of the dump.
+    offset: Cell<u64>,
+}
+
+impl Serialize for SummarySlot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // The key of the field is written already, so the value starts here.
+        self.offset.set(self.written.get());
+        serde_json::value::RawValue::from_string(summary_json(&DumpSummary::default()))
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+/// A summary as compact JSON, padded so that any summary has the same length.
+pub(crate) fn summary_json(summary: &DumpSummary) -> String {
+    let largest = DumpSummary {
+        vanished: usize::MAX,
+        retyped: usize::MAX,
+    };
+    let width = serde_json::to_string(&largest)
+        .expect("a serializable summary")
+        .len();
+    let json = serde_json::to_string(summary).expect("a serializable summary");
+    // JSON allows whitespace before the closing brace.
+    format!(
+        "{}{}}}",
+        &json[..json.len() - 1],
+        " ".repeat(width - json.len())
+    )
+}
+
+/// A writer counting the bytes written through it.
+struct CountingWriter<'a, W> {
+    inner: W,
+    written: &'a Cell<u64>,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written.set(self.written.get() + len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct StreamedDatabases<'a> {
+    rd: RefCell<&'a mut RedisDump>,
+    dbs: &'a [u32],
+    /// The first error returned by the server, if any.
+    error: RefCell<Option<Error>>,
+}
+
+impl StreamedDatabases<'_> {
+    /// Keep the error to report it, and abort the serialization.
+    fn fail<E: serde::ser::Error>(&self, err: Error) -> E {
+        let msg = err.to_string();
+        self.error.borrow_mut().get_or_insert(err);
+        E::custom(msg)
+    }
+}
+
+impl Serialize for StreamedDatabases<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.dbs.len()))?;
+        for &db in self.dbs {
+            map.serialize_entry(
+                &db,
+                &StreamedEntries {
+                    databases: self,
+                    db,
                 },
-            );
+            )?;
         }
-        Ok(entries)
+        map.end()
+    }
+}
+
+/// The entries of a single database, see [`StreamedDatabases`].
+struct StreamedEntries<'a, 'b> {
+    databases: &'b StreamedDatabases<'a>,
+    db: u32,
+}
+
+impl Serialize for StreamedEntries<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut rd = self.databases.rd.borrow_mut();
+        rd.select_db(self.db)
+            .map_err(|err| self.databases.fail::<S::Error>(err))?;
+        let mut map = serializer.serialize_map(None)?;
+        for item in rd.iter() {
+            let (key, entry) = item.map_err(|err| self.databases.fail::<S::Error>(err))?;
+            map.serialize_entry(&key, &entry)?;
+        }
+        map.end()
     }
 }
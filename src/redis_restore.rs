@@ -1,60 +1,269 @@
-use std::collections::HashMap;
-
-use anyhow::anyhow;
-use redis::Commands;
 use url::Url;
 
 use crate::{
-    __private::{consts::REDIS_DEFAULT_URL, utils::get_database_from_url},
-    types::RedisValue,
+    __private::{
+        consts::REDIS_DEFAULT_URL,
+        utils::{get_database_from_url, get_info_field},
+    },
+    connection::{open_client, Credentials, SentinelOptions, SentinelServer, TlsOptions},
+    key_pattern::{matches_any, KeyPattern},
+    types::{
+        KeyError, RedisBytes, RedisDumpFile, RedisEntries, RedisEntry, RedisStream, RedisValue,
+    },
+    Error,
 };
 
+mod cluster;
+mod reader;
+
+use cluster::RestoreCluster;
+
+/// A consumer group used to create empty streams. It is destroyed right after.
+const TMP_STREAM_GROUP: &str = "redis-tools:tmp";
+/// The default number of keys written at once, see [`RedisRestoreBuilder::with_batch_size`].
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+/// The default number of members written at once, see [`RedisRestoreBuilder::with_chunk_size`].
+pub const DEFAULT_CHUNK_SIZE: usize = 10_000;
+
+/// A filter on the keys to restore.
+///
+/// Filters can be combined: a key is restored only if it passes all of them.
 #[derive(Default)]
 pub enum RestoreFilter {
     #[default]
     None,
+    /// Restore only keys of the given types.
     Keys(Vec<String>),
+    /// Restore only keys matching at least one of the patterns.
+    Include(Vec<KeyPattern>),
+    /// Don't restore keys matching any of the patterns.
+    Exclude(Vec<KeyPattern>),
+}
+
+/// What to do with keys of the dump that already exist in the target database.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Delete the existing key, and restore the dumped one in its place.
+    #[default]
+    Replace,
+    /// Keep the existing key, and don't restore the dumped one.
+    Skip,
+    /// Merge the dumped key into the existing one (strings are overwritten).
+    ///
+    /// Raw payloads can't be merged, so restoring them fails if the key exists.
+    Merge,
+    /// Abort before writing anything, if any key already exists.
+    Fail,
+}
+
+/// How the expiry of restored keys is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExpiryMode {
+    /// Keep the TTL the key had when dumped, counted from the restore (`PEXPIRE`).
+    #[default]
+    Relative,
+    /// Expire the key when it would have expired on the dumped server (`PEXPIREAT`).
+    ///
+    /// Entries dumped without their expiry time fall back to their TTL.
+    Absolute,
+}
+
+/// Statistics of a restore.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RestoreStats {
+    /// The number of keys restored.
+    pub restored: usize,
+    /// The number of keys skipped by the filter or the source database.
+    pub filtered: usize,
+    /// The number of keys skipped because they already existed (see [`ConflictPolicy::Skip`]).
+    pub existing: usize,
+    /// The number of keys skipped because they had already expired
+    /// (see [`RedisRestoreBuilder::with_skip_expired`]).
+    pub expired: usize,
+    /// The number of keys that couldn't be restored, when keeping going
+    /// (see [`RedisRestoreBuilder::with_keep_going`]).
+    pub failed: usize,
 }
 
 pub struct RedisRestore {
     conn: redis::Connection,
     db: u32,
-    //FIXME
-    #[allow(dead_code)]
-    filter: RestoreFilter,
+    filters: Vec<RestoreFilter>,
+    source_db: Option<u32>,
+    conflict_policy: ConflictPolicy,
+    expiry_mode: ExpiryMode,
+    skip_expired: bool,
+    batch_size: usize,
+    chunk_size: usize,
+    transaction: bool,
+    keep_going: bool,
+    errors: Vec<KeyError>,
+    cluster: Option<RestoreCluster>,
+    /// Whether the master was found through sentinels.
+    sentinel: bool,
+    /// Whether the server keeps the counters of streams (see [`RedisStream::entries_added`]).
+    stream_counters: bool,
 }
 
 pub struct RedisRestoreBuilder {
     url: Url,
-    filter: RestoreFilter,
+    credentials: Credentials,
+    tls: TlsOptions,
+    cluster: bool,
+    sentinel: Option<SentinelOptions>,
+    filters: Vec<RestoreFilter>,
+    source_db: Option<u32>,
+    conflict_policy: ConflictPolicy,
+    expiry_mode: ExpiryMode,
+    skip_expired: bool,
+    batch_size: usize,
+    chunk_size: usize,
+    transaction: bool,
+    keep_going: bool,
+}
+
+impl Default for RedisRestoreBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RedisRestoreBuilder {
     pub fn new() -> Self {
         Self {
             url: Url::parse(REDIS_DEFAULT_URL).expect("Default URL should be a valid URL"),
-            filter: RestoreFilter::None,
+            credentials: Credentials::default(),
+            tls: TlsOptions::default(),
+            cluster: false,
+            sentinel: None,
+            filters: Vec::new(),
+            source_db: None,
+            conflict_policy: ConflictPolicy::default(),
+            expiry_mode: ExpiryMode::default(),
+            skip_expired: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            transaction: false,
+            keep_going: false,
         }
     }
     pub fn with_url(mut self, url: Url) -> Self {
         self.url = url;
         self
     }
+    /// Authenticate with the given credentials, rather than with the ones of the URL.
+    ///
+    /// They are also used for the nodes of a cluster, and for servers found through Sentinel.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+    /// Set the TLS options, for a `rediss://` URL.
+    pub fn with_tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = tls;
+        self
+    }
+    /// Restore into a Redis Cluster, whose topology is discovered from the node at the URL.
+    ///
+    /// Each key is written to the node serving its slot, wherever it was dumped from.
+    /// A cluster only has database 0, so only dumps of a single database can be restored.
+    pub fn with_cluster(mut self, cluster: bool) -> Self {
+        self.cluster = cluster;
+        self
+    }
+    /// Restore into the master monitored by the given sentinels, rather than the server at the URL.
+    ///
+    /// The sentinels are only asked when connecting. If the master fails over during the
+    /// restore, the restore fails: writes are replicated asynchronously, so the new master may
+    /// miss any of the keys written so far, and the restore must be run again (with
+    /// [`ConflictPolicy::Replace`], since some keys may be partly written).
+    pub fn with_sentinel(mut self, sentinel: SentinelOptions) -> Self {
+        self.sentinel = Some(sentinel);
+        self
+    }
+    /// Add a filter on the keys to restore. A key is restored only if it passes all filters.
     pub fn with_filter(mut self, filter: RestoreFilter) -> Self {
-        self.filter = filter;
+        self.filters.push(filter);
+        self
+    }
+    /// Restore only the given database of the dump, or all of them if `None`.
+    pub fn with_source_db(mut self, source_db: Option<u32>) -> Self {
+        self.source_db = source_db;
+        self
+    }
+    pub fn with_conflict_policy(mut self, conflict_policy: ConflictPolicy) -> Self {
+        self.conflict_policy = conflict_policy;
+        self
+    }
+    pub fn with_expiry_mode(mut self, expiry_mode: ExpiryMode) -> Self {
+        self.expiry_mode = expiry_mode;
+        self
+    }
+    /// Don't restore keys whose expiry time has passed, by the clock of the target server.
+    pub fn with_skip_expired(mut self, skip_expired: bool) -> Self {
+        self.skip_expired = skip_expired;
+        self
+    }
+    /// Write keys in batches of the given size, each sent as a single pipeline.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+    /// Write hashes, sets, sorted sets and lists with more members than the given size
+    /// with several commands, each adding at most that many members.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+    /// Wrap each batch in a `MULTI`/`EXEC` transaction, so other clients never see it half-written.
+    pub fn with_transaction(mut self, transaction: bool) -> Self {
+        self.transaction = transaction;
         self
     }
-    pub fn connect(self) -> anyhow::Result<RedisRestore> {
-        let conn = redis::Client::open(self.url.as_str())?.get_connection()?;
-        let db = if let Some(db) = get_database_from_url(&self.url) {
-            db
+    /// Skip keys that can't be written instead of failing, and keep them in
+    /// [`RedisRestore::errors`]. Errors of the connection itself still fail the restore.
+    pub fn with_keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+    pub fn connect(mut self) -> Result<RedisRestore, Error> {
+        self.url = self.credentials.apply(&self.url)?;
+        if self.cluster && self.sentinel.is_some() {
+            return Err(Error::Connection(redis::RedisError::from((
+                redis::ErrorKind::InvalidClientConfig,
+                "A cluster isn't monitored by Sentinel",
+            ))));
+        }
+        let sentinel = self.sentinel.map(|sentinel| {
+            SentinelServer::new(self.url.clone(), self.tls.clone(), sentinel, false)
+        });
+        let mut conn = match &sentinel {
+            Some(sentinel) => sentinel.connect()?,
+            None => open_client(&self.url, &self.tls)?.get_connection()?,
+        };
+        let db = get_database_from_url(&self.url).unwrap_or_default();
+        let stream_counters = has_stream_counters(&mut conn);
+        let cluster = if self.cluster {
+            Some(RestoreCluster::discover(&mut conn, self.url, self.tls)?)
         } else {
-            0
+            None
         };
         Ok(RedisRestore {
             conn,
             db,
-            filter: self.filter,
+            filters: self.filters,
+            source_db: self.source_db,
+            conflict_policy: self.conflict_policy,
+            expiry_mode: self.expiry_mode,
+            skip_expired: self.skip_expired,
+            batch_size: self.batch_size,
+            chunk_size: self.chunk_size,
+            transaction: self.transaction,
+            keep_going: self.keep_going,
+            errors: Vec::new(),
+            cluster,
+            sentinel: sentinel.is_some(),
+            stream_counters,
         })
     }
 }
@@ -79,52 +288,631 @@ impl RedisRestore {
         &mut self.conn
     }
 
+    /// Keys that couldn't be restored since the connection, when keeping going
+    /// (see [`RedisRestoreBuilder::with_keep_going`]).
+    ///
+    pub fn errors(&self) -> &[KeyError] {
+        &self.errors
+    }
+
     /// Select the active database.
     ///
     /// This is a no-op if the database is already selected.
-    pub fn select_db(&mut self, db: u32) -> Result<(), anyhow::Error> {
+    /// In a cluster, the database of the dump is restored into database 0, and selecting
+    /// another one after fails.
+    pub fn select_db(&mut self, db: u32) -> Result<(), Error> {
+        if let Some(cluster) = &mut self.cluster {
+            match cluster.db {
+                Some(restored) if restored != db => {
+                    return Err(Error::MultipleDatabases {
+                        dbs: vec![restored, db],
+                    })
+                }
+                _ => cluster.db = Some(db),
+            }
+            self.db = db;
+            return Ok(());
+        }
         if self.db == db {
             return Ok(());
         }
-        redis::cmd("SELECT").arg(db).query(&mut self.conn)?;
+        redis::cmd("SELECT").arg(db).query::<()>(&mut self.conn)?;
         self.db = db;
         Ok(())
     }
 
     /// Restore the Redis database.
-    pub fn fill_db(&mut self, entries: HashMap<String, RedisValue>) -> Result<(), anyhow::Error> {
-        for (key, rv) in entries {
-            if let RedisValue::Meta(metadata) = rv {
-                self.select_db(metadata.db)?;
-                match *metadata.data {
-                    RedisValue::String(string) => {
-                        self.conn.set(&key, string)?;
+    ///
+    /// Each database in the dump is restored into the database with the same index
+    /// (or into database 0, in a cluster).
+    /// Keys that already exist are handled according to the conflict policy.
+    pub fn fill_db(&mut self, dump: RedisDumpFile) -> Result<RestoreStats, Error> {
+        let mut stats = RestoreStats::default();
+        if self.cluster.is_some() {
+            let dbs = restored_dbs(&dump, self.source_db)
+                .map(|(db, _)| db)
+                .collect::<Vec<_>>();
+            if dbs.len() > 1 {
+                return Err(Error::MultipleDatabases { dbs });
+            }
+        }
+        if self.conflict_policy == ConflictPolicy::Fail {
+            self.check_conflicts(&dump)?;
+        }
+        for (db, entries) in dump.databases {
+            if !self.is_restored_db(db) {
+                stats.filtered += entries.len();
+                continue;
+            }
+            if self.select_entries_db(db, entries.len())? {
+                self.fill_entries(entries, &mut stats)?;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Select the database of the dump that entries are restored from, unless there are none.
+    ///
+    /// An empty database isn't selected, so it isn't restored into a cluster. Returns whether
+    /// the database was selected.
+    fn select_entries_db(&mut self, db: u32, entries: usize) -> Result<bool, Error> {
+        if entries == 0 {
+            return Ok(false);
+        }
+        self.select_db(db)?;
+        Ok(true)
+    }
+
+    /// Whether the given database of the dump is restored.
+    fn is_restored_db(&self, db: u32) -> bool {
+        self.source_db.is_none_or(|source_db| source_db == db)
+    }
+
+    /// Whether the entry is excluded by the filters.
+    fn is_filtered(&self, key: &[u8], entry: &RedisEntry) -> bool {
+        !self.filters.iter().all(|filter| match filter {
+            RestoreFilter::None => true,
+            RestoreFilter::Keys(key_types) => key_types
+                .iter()
+                .any(|key_type| key_type == entry.value.key_type()),
+            RestoreFilter::Include(patterns) => matches_any(patterns, key),
+            RestoreFilter::Exclude(patterns) => !matches_any(patterns, key),
+        })
+    }
+
+    /// Fail if any key of the dump already exists in the target.
+    fn check_conflicts(&mut self, dump: &RedisDumpFile) -> Result<(), Error> {
+        let mut conflicts = Vec::new();
+        for (db, entries) in restored_dbs(dump, self.source_db) {
+            self.select_entries_db(db, entries.len())?;
+            self.find_conflicts(entries, &mut conflicts)?;
+        }
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Conflict { keys: conflicts })
+    }
+
+    /// Add the keys that already exist in the active database to the conflicts, leaving out
+    /// the filtered ones.
+    fn find_conflicts<'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = (&'a RedisBytes, &'a RedisEntry)>,
+        conflicts: &mut Vec<(u32, RedisBytes)>,
+    ) -> Result<(), Error> {
+        let keys = entries
+            .into_iter()
+            .filter(|(key, entry)| !self.is_filtered(key, entry))
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for batch in keys.chunks(self.batch_size) {
+            for (key, exists) in batch.iter().zip(self.exist(batch)?) {
+                if exists {
+                    conflicts.push((self.db, (*key).clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether each of the keys exists, with a single pipeline.
+    fn exist(&mut self, keys: &[&RedisBytes]) -> Result<Vec<bool>, Error> {
+        if let Some(cluster) = &mut self.cluster {
+            let mut pipe = WritePipeline::default();
+            for &key in keys {
+                pipe.cmd(key, "EXISTS").arg(key);
+            }
+            return cluster
+                .query(&pipe)?
+                .into_iter()
+                .zip(keys)
+                .map(|(reply, key)| {
+                    reply
+                        .and_then(|reply| redis::from_redis_value(&reply))
+                        .map_err(|err| Error::from(err).with_key(key))
+                })
+                .collect();
+        }
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.exists(*key);
+        }
+        Ok(pipe.query(&mut self.conn)?)
+    }
+
+    /// Restore a single database worth of entries into the active database.
+    ///
+    /// Entries are written in batches, each sent as a single pipeline.
+    fn fill_entries(
+        &mut self,
+        entries: impl IntoIterator<Item = (RedisBytes, RedisEntry)>,
+        stats: &mut RestoreStats,
+    ) -> Result<(), Error> {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        for (key, entry) in entries {
+            if self.is_filtered(&key, &entry) {
+                stats.filtered += 1;
+                continue;
+            }
+            batch.push((key, entry));
+            if batch.len() == self.batch_size {
+                self.fill_sentinel_batch(&batch, stats)?;
+                batch.clear();
+            }
+        }
+        self.fill_sentinel_batch(&batch, stats)
+    }
+
+    /// Restore a batch of entries, failing if the master found through sentinels may have
+    /// failed over meanwhile (see [`RedisRestoreBuilder::with_sentinel`]).
+    fn fill_sentinel_batch(
+        &mut self,
+        batch: &[(RedisBytes, RedisEntry)],
+        stats: &mut RestoreStats,
+    ) -> Result<(), Error> {
+        match self.fill_batch(batch, stats) {
+            Err(err) if self.sentinel => Err(err.into_failover()),
+            result => result,
+        }
+    }
+
+    /// The current time of the server, as a Unix timestamp in milliseconds.
+    fn server_time(&mut self) -> Result<u64, Error> {
+        let (secs, micros): (u64, u64) = redis::cmd("TIME").query(&mut self.conn)?;
+        Ok(secs * 1000 + micros / 1000)
+    }
+
+    /// Restore a batch of entries, with a single pipeline (and `EXISTS` pipeline, if skipping).
+    fn fill_batch(
+        &mut self,
+        batch: &[(RedisBytes, RedisEntry)],
+        stats: &mut RestoreStats,
+    ) -> Result<(), Error> {
+        let now_ms = if self.skip_expired && batch.iter().any(|(_, e)| e.expire_at.is_some()) {
+            Some(self.server_time()?)
+        } else {
+            None
+        };
+        let (expired, batch): (Vec<_>, Vec<_>) = batch.iter().partition(
+            |(_, entry)| matches!((now_ms, entry.expire_at), (Some(now), Some(at)) if at <= now),
+        );
+        stats.expired += expired.len();
+
+        let skipped = if self.conflict_policy == ConflictPolicy::Skip {
+            self.exist(&batch.iter().map(|(key, _)| key).collect::<Vec<_>>())?
+        } else {
+            vec![false; batch.len()]
+        };
+
+        let mut pipe = WritePipeline::default();
+        for (&(key, entry), skipped) in batch.iter().zip(skipped) {
+            if skipped {
+                stats.existing += 1;
+                continue;
+            }
+            let expiry = self.expiry(entry);
+            // RESTORE replaces the key itself.
+            if self.conflict_policy == ConflictPolicy::Replace
+                && !matches!(entry.value, RedisValue::Raw(_))
+            {
+                pipe.cmd(key, "DEL").arg(key);
+            }
+            match &entry.value {
+                RedisValue::String(string) => {
+                    pipe.cmd(key, "SET").arg(key).arg(string);
+                }
+                // Big collections are written in chunks, so no single command blocks the server.
+                RedisValue::List(list) => {
+                    // Lists are dumped head to tail, so they are pushed back to the tail.
+                    for chunk in list.chunks(self.chunk_size) {
+                        pipe.cmd(key, "RPUSH").arg(key).arg(chunk);
+                    }
+                }
+                RedisValue::Set(set) => {
+                    let members = set.iter().collect::<Vec<_>>();
+                    for chunk in members.chunks(self.chunk_size) {
+                        pipe.cmd(key, "SADD").arg(key).arg(chunk);
+                    }
+                }
+                RedisValue::Hash(hashmap) => {
+                    let fields = hashmap.iter().collect::<Vec<_>>();
+                    for chunk in fields.chunks(self.chunk_size) {
+                        let hset = pipe.cmd(key, "HSET").arg(key);
+                        for (field, value) in chunk {
+                            hset.arg(field).arg(value);
+                        }
                     }
-                    RedisValue::List(list) => {
-                        self.conn.lpush(&key, list)?;
+                }
+                RedisValue::ZSet(zset) => {
+                    // Scores are sent as their shortest exact decimal representation (or
+                    // `inf`/`-inf`), since the redis crate can't format infinite floats.
+                    for chunk in zset.chunks(self.chunk_size) {
+                        let zadd = pipe.cmd(key, "ZADD").arg(key);
+                        for (member, score) in chunk {
+                            zadd.arg(score.to_string()).arg(member);
+                        }
                     }
-                    RedisValue::Set(set) => {
-                        self.conn.sadd(&key, set)?;
+                }
+                RedisValue::Stream(stream) => {
+                    // The counters only hold for the dumped entries, not for merged ones.
+                    let counters =
+                        self.stream_counters && self.conflict_policy != ConflictPolicy::Merge;
+                    fill_stream(&mut pipe, key, stream, counters);
+                }
+                RedisValue::Raw(raw) => {
+                    // RESTORE sets the expiry itself: 0 means none, ABSTTL an absolute time.
+                    let restore = pipe.cmd(key, "RESTORE").arg(key);
+                    match expiry {
+                        Some(Expiry::At(expire_at)) => restore.arg(expire_at),
+                        Some(Expiry::In(pttl)) => restore.arg(pttl),
+                        None => restore.arg(0),
+                    };
+                    restore.arg(&raw.payload);
+                    if self.conflict_policy == ConflictPolicy::Replace {
+                        restore.arg("REPLACE");
                     }
-                    RedisValue::Hash(hashmap) => {
-                        let tupleslice =
-                            hashmap.into_iter().map(|(k, v)| (k, v)).collect::<Vec<_>>();
-                        self.conn.hset_multiple(&key, &tupleslice)?;
+                    if let Some(Expiry::At(_)) = expiry {
+                        restore.arg("ABSTTL");
                     }
-                    RedisValue::ZSet(zset) => {
-                        let score_first_zset =
-                            zset.into_iter().map(|(k, v)| (v, k)).collect::<Vec<_>>();
-                        self.conn.zadd_multiple(&key, &score_first_zset)?;
+                }
+            };
+            if !matches!(entry.value, RedisValue::Raw(_)) {
+                match expiry {
+                    Some(Expiry::At(expire_at)) => {
+                        pipe.cmd(key, "PEXPIREAT").arg(key).arg(expire_at);
                     }
-                    _ => {
-                        return Err(anyhow!("{}: Unsupported type", &key)); //TODO better error
+                    Some(Expiry::In(pttl)) => {
+                        pipe.cmd(key, "PEXPIRE").arg(key).arg(pttl);
                     }
-                };
-                if metadata.ttl > 0 {
-                    self.conn.expire(key, metadata.ttl as usize)?;
+                    None => {}
+                }
+            }
+            stats.restored += 1;
+        }
+        let failures = self.send(pipe)?;
+        if failures.is_empty() {
+            return Ok(());
+        }
+        if !self.keep_going {
+            let failure = failures.into_iter().next().expect("a failure");
+            let mut keys = failure.keys.into_iter().cloned();
+            return Err(if failure.transaction {
+                Error::Transaction {
+                    keys: keys.collect(),
+                    source: failure.error,
+                }
+            } else {
+                let err = Error::from(failure.error);
+                match keys.next() {
+                    Some(key) => err.with_key(&key),
+                    None => err,
                 }
+            });
+        }
+
+        // Redis doesn't roll back a transaction, so only the keys of failed commands failed
+        // (all of them if the transaction was aborted), each with its first error.
+        let mut failed: Vec<(&RedisBytes, String)> = Vec::new();
+        for failure in failures {
+            let reason = Error::from(failure.error).reason();
+            let reason = if failure.transaction {
+                format!("{} (in a transaction)", reason)
+            } else {
+                reason
             };
+            for key in failure.keys {
+                if failed.iter().all(|(failed, _)| *failed != key) {
+                    failed.push((key, reason.clone()));
+                }
+            }
+        }
+        stats.restored -= failed.len();
+        stats.failed += failed.len();
+        for (key, reason) in failed {
+            let key_type = batch
+                .iter()
+                .find(|(k, _)| k == key)
+                .map_or("", |(_, entry)| entry.value.key_type());
+            self.errors.push(KeyError {
+                key: key.clone(),
+                db: self.db,
+                key_type: key_type.to_string(),
+                reason,
+            });
         }
         Ok(())
     }
+
+    /// Send a pipeline of writes, and return the commands that failed.
+    fn send<'a>(&mut self, pipe: WritePipeline<'a>) -> Result<Vec<Failure<'a>>, Error> {
+        match &mut self.cluster {
+            Some(cluster) if self.transaction => cluster.send_transactions(pipe),
+            Some(cluster) => cluster.send(&pipe),
+            None => {
+                let mut failures = pipe.send(&mut self.conn, self.transaction)?;
+                // A master that became a replica fails the whole restore, even when keeping
+                // going, rather than each of the keys left.
+                let read_only = failures
+                    .iter()
+                    .position(|failure| failure.error.kind() == redis::ErrorKind::ReadOnly);
+                if let (Some(i), true) = (read_only, self.sentinel) {
+                    let failure = failures.swap_remove(i);
+                    return Err(Error::Protocol {
+                        key: failure.keys.first().map(|key| (*key).clone()),
+                        source: failure.error,
+                    });
+                }
+                Ok(failures)
+            }
+        }
+    }
+
+    /// When the restored key should expire, if ever, according to the expiry mode.
+    fn expiry(&self, entry: &RedisEntry) -> Option<Expiry> {
+        match (self.expiry_mode, entry.expire_at) {
+            (ExpiryMode::Absolute, Some(expire_at)) => Some(Expiry::At(expire_at)),
+            _ => entry.pttl.filter(|pttl| *pttl > 0).map(Expiry::In),
+        }
+    }
+}
+
+/// The databases of the dump that are restored and selected (see
+/// [`RedisRestore::select_entries_db`]), with their entries.
+pub(crate) fn restored_dbs(
+    dump: &RedisDumpFile,
+    source_db: Option<u32>,
+) -> impl Iterator<Item = (u32, &RedisEntries)> {
+    dump.databases
+        .iter()
+        .filter(move |(db, entries)| {
+            source_db.is_none_or(|source_db| source_db == **db) && !entries.is_empty()
+        })
+        .map(|(db, entries)| (*db, entries))
+}
+
+/// The expiry of a restored key.
+enum Expiry {
+    /// At a Unix timestamp in milliseconds.
+    At(u64),
+    /// In a number of milliseconds.
+    In(i64),
+}
+
+/// Whether the server is Redis 7 or later, which keeps the counters of streams used to compute
+/// the lag of consumer groups. Servers whose version can't be read are taken for older ones.
+fn has_stream_counters(conn: &mut redis::Connection) -> bool {
+    redis::cmd("INFO")
+        .arg("server")
+        .query::<String>(conn)
+        .ok()
+        .and_then(|info| get_info_field(&info, "redis_version"))
+        .and_then(|version| version.split('.').next()?.parse::<u32>().ok())
+        .is_some_and(|major| major >= 7)
+}
+
+/// Add the commands restoring a stream, along with its consumer groups and their pending
+/// entries.
+///
+/// With `counters`, the counters of the stream and of its groups are restored too (when they
+/// were dumped), so the lag of the groups is the same as on the dumped server.
+fn fill_stream<'a>(
+    pipe: &mut WritePipeline<'a>,
+    key: &'a RedisBytes,
+    stream: &RedisStream,
+    counters: bool,
+) {
+    for entry in &stream.entries {
+        pipe.cmd(key, "XADD")
+            .arg(key)
+            .arg(&entry.id)
+            .arg(&entry.fields);
+    }
+    if stream.entries.is_empty() {
+        // XADD can't create an empty stream, but XGROUP CREATE can.
+        pipe.cmd(key, "XGROUP")
+            .arg("CREATE")
+            .arg(key)
+            .arg(TMP_STREAM_GROUP)
+            .arg("$")
+            .arg("MKSTREAM");
+        pipe.cmd(key, "XGROUP")
+            .arg("DESTROY")
+            .arg(key)
+            .arg(TMP_STREAM_GROUP);
+    }
+    // The last generated ID may be past the last entry, if entries were deleted.
+    let xsetid = pipe.cmd(key, "XSETID").arg(key).arg(&stream.last_id);
+    if counters {
+        if let Some(entries_added) = stream.entries_added {
+            xsetid.arg("ENTRIESADDED").arg(entries_added);
+        }
+        if let Some(max_deleted_entry_id) = &stream.max_deleted_entry_id {
+            xsetid.arg("MAXDELETEDID").arg(max_deleted_entry_id);
+        }
+    }
+
+    for group in &stream.groups {
+        let create = pipe
+            .cmd(key, "XGROUP")
+            .arg("CREATE")
+            .arg(key)
+            .arg(&group.name)
+            .arg(&group.last_delivered_id);
+        if let (true, Some(entries_read)) = (counters, group.entries_read) {
+            create.arg("ENTRIESREAD").arg(entries_read);
+        }
+        // Claiming an entry creates its consumer, so only idle consumers are created explicitly.
+        for consumer in &group.consumers {
+            if group
+                .pending
+                .iter()
+                .all(|pending| &pending.consumer != consumer)
+            {
+                pipe.cmd(key, "XGROUP")
+                    .arg("CREATECONSUMER")
+                    .arg(key)
+                    .arg(&group.name)
+                    .arg(consumer);
+            }
+        }
+        // Rebuild the PEL, with the delivery counts and idle times it had when dumped. Entries
+        // that were deleted from the stream (e.g. by XTRIM) can't be claimed, so they are lost.
+        for pending in &group.pending {
+            pipe.cmd(key, "XCLAIM")
+                .arg(key)
+                .arg(&group.name)
+                .arg(&pending.consumer)
+                .arg(0)
+                .arg(&pending.id)
+                .arg("IDLE")
+                .arg(pending.idle)
+                .arg("RETRYCOUNT")
+                .arg(pending.delivery_count)
+                .arg("FORCE")
+                .arg("JUSTID");
+        }
+    }
+}
+
+/// A pipeline of writes, remembering the key each command writes to.
+#[derive(Default)]
+struct WritePipeline<'a> {
+    pipe: redis::Pipeline,
+    keys: Vec<&'a RedisBytes>,
+}
+
+impl<'a> WritePipeline<'a> {
+    /// Add a command writing to the given key. Its arguments are added to the returned pipeline.
+    fn cmd(&mut self, key: &'a RedisBytes, name: &str) -> &mut redis::Pipeline {
+        self.keys.push(key);
+        self.pipe.cmd(name)
+    }
+
+    /// Split the commands into several pipelines, by the group of their key, keeping their order.
+    fn split<T: PartialEq>(self, group: impl Fn(&RedisBytes) -> T) -> Vec<(T, WritePipeline<'a>)> {
+        let mut pipes: Vec<(T, WritePipeline<'a>)> = Vec::new();
+        for (key, cmd) in self.keys.into_iter().zip(self.pipe.cmd_iter()) {
+            let target = group(key);
+            let pipe = match pipes.iter().position(|(t, _)| *t == target) {
+                Some(i) => &mut pipes[i].1,
+                None => {
+                    pipes.push((target, WritePipeline::default()));
+                    &mut pipes.last_mut().expect("a pipeline").1
+                }
+            };
+            pipe.keys.push(key);
+            pipe.pipe.add_command(cmd.clone());
+        }
+        pipes
+    }
+
+    /// Send the pipeline, optionally as a transaction, and return the commands that failed.
+    ///
+    /// Every reply is read, even after an error, so each failing command can be reported
+    /// along with its key, including the commands of a transaction that failed when run by
+    /// `EXEC`. A transaction aborted by `EXEC` (e.g. after an error while queuing it) fails
+    /// all of its keys.
+    fn send(
+        &self,
+        conn: &mut redis::Connection,
+        transaction: bool,
+    ) -> Result<Vec<Failure<'a>>, Error> {
+        if self.keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let packed = if transaction {
+            let mut pipe = self.pipe.clone();
+            pipe.atomic();
+            pipe.get_packed_pipeline()
+        } else {
+            self.pipe.get_packed_pipeline()
+        };
+        conn.send_packed_command(&packed)?;
+
+        // The commands of a key are consecutive.
+        let all_keys = || {
+            let mut keys = self.keys.clone();
+            keys.dedup();
+            keys
+        };
+        let mut failures = Vec::new();
+        let recv = |conn: &mut redis::Connection| match conn.recv_response() {
+            // The connection is lost, so there are no replies left to read.
+            Err(e) if e.is_io_error() => Err(Error::from(e)),
+            Err(e) => Ok(Err(e)),
+            Ok(redis::Value::ServerError(e)) => Ok(Err(e.into())),
+            Ok(reply) => Ok(Ok(reply)),
+        };
+        if transaction {
+            if let Err(error) = recv(conn)? {
+                failures.push(Failure {
+                    keys: all_keys(),
+                    transaction: true,
+                    error,
+                });
+            }
+        }
+        for key in &self.keys {
+            if let Err(error) = recv(conn)?.and_then(redis::Value::extract_error) {
+                failures.push(Failure {
+                    keys: vec![*key],
+                    transaction: false,
+                    error,
+                });
+            }
+        }
+        if transaction {
+            match recv(conn)? {
+                // The reply to each command run by `EXEC`, in order: Redis doesn't roll
+                // back a transaction, so only the failed commands didn't write their key.
+                Ok(redis::Value::Array(replies)) => {
+                    for (key, reply) in self.keys.iter().zip(replies) {
+                        if let Err(error) = reply.extract_error() {
+                            failures.push(Failure {
+                                keys: vec![*key],
+                                transaction: true,
+                                error,
+                            });
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(error) => failures.push(Failure {
+                    keys: all_keys(),
+                    transaction: true,
+                    error,
+                }),
+            }
+        }
+        Ok(failures)
+    }
+}
+
+/// A command of a [`WritePipeline`] that failed.
+struct Failure<'a> {
+    /// The key written by the command, or all the keys of the transaction if it was aborted.
+    keys: Vec<&'a RedisBytes>,
+    /// Whether the command failed when its transaction was run (or aborted) by `EXEC`.
+    transaction: bool,
+    error: redis::RedisError,
 }
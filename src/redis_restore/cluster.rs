@@ -0,0 +1,253 @@
+//! Restore into a Redis Cluster, each key being written to the node serving its slot.
+//!
+//! Slots are computed from the keys, rather than taken from the dump, so a dump can be
+//! restored into a cluster of another shape than the one it was taken from.
+use std::collections::{hash_map::Entry, HashMap};
+
+use url::Url;
+
+use super::{Failure, WritePipeline};
+use crate::{
+    cluster::{discover, key_slot, ClusterNode},
+    connection::{open_client, server_url, TlsOptions},
+    Error,
+};
+
+/// The number of times a command is redirected to another node before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// The address of a node, as `(host, port)`.
+type Node = (String, u16);
+
+/// The cluster a dump is restored into.
+pub(super) struct RestoreCluster {
+    seed: Url,
+    tls: TlsOptions,
+    /// The primaries of the cluster, as last discovered.
+    nodes: Vec<ClusterNode>,
+    /// The connections to the nodes, opened when first needed.
+    conns: HashMap<Node, redis::Connection>,
+    /// The database of the dump restored into database 0, the only one of a cluster.
+    pub(super) db: Option<u32>,
+}
+
+/// Where to send a command.
+#[derive(Clone, PartialEq, Eq)]
+struct Target {
+    node: Node,
+    /// Whether the command must follow `ASKING`, its slot being migrated to the node.
+    asking: bool,
+}
+
+impl RestoreCluster {
+    /// Discover the primaries of the cluster, through a connection to one of its nodes.
+    pub(super) fn discover(
+        conn: &mut redis::Connection,
+        seed: Url,
+        tls: TlsOptions,
+    ) -> Result<Self, Error> {
+        let nodes = discover(conn, seed.host_str().unwrap_or_default(), false)?;
+        if nodes.is_empty() {
+            return Err(Error::protocol("The cluster doesn't serve any slot"));
+        }
+        Ok(RestoreCluster {
+            seed,
+            tls,
+            nodes,
+            conns: HashMap::new(),
+            db: None,
+        })
+    }
+
+    /// Send commands, each to the node serving the slot of its key, and return their replies
+    /// in order.
+    ///
+    /// Commands are pipelined to each node, and redirected commands are sent again to the node
+    /// they were redirected to.
+    pub(super) fn query(
+        &mut self,
+        pipe: &WritePipeline<'_>,
+    ) -> Result<Vec<redis::RedisResult<redis::Value>>, Error> {
+        let cmds = pipe
+            .keys
+            .iter()
+            .zip(pipe.pipe.cmd_iter())
+            .collect::<Vec<_>>();
+        let mut replies = cmds.iter().map(|_| None).collect::<Vec<_>>();
+        let mut pending = cmds
+            .iter()
+            .enumerate()
+            .map(|(i, (key, _))| (i, self.owner(key_slot(key))))
+            .collect::<Vec<_>>();
+        for _ in 0..=MAX_REDIRECTS {
+            let mut targets: Vec<Target> = Vec::new();
+            for (_, target) in &pending {
+                if !targets.contains(target) {
+                    targets.push(target.clone());
+                }
+            }
+            let mut redirected = Vec::new();
+            let mut moved = None;
+            for target in targets {
+                let sent = pending
+                    .iter()
+                    .filter(|(_, t)| *t == target)
+                    .map(|(i, _)| *i)
+                    .collect::<Vec<_>>();
+                let mut batch = redis::pipe();
+                for &i in &sent {
+                    // `ASKING` only applies to the command following it.
+                    if target.asking {
+                        batch.cmd("ASKING");
+                    }
+                    batch.add_command(cmds[i].1.clone());
+                }
+                let conn = self.conn(&target.node)?;
+                conn.send_packed_command(&batch.get_packed_pipeline())?;
+                for &i in &sent {
+                    if target.asking {
+                        // The reply to `ASKING`: the reply to the command tells if it worked.
+                        let _ = recv(conn)?;
+                    }
+                    let reply = recv(conn)?;
+                    if let Some(next) = reply.as_ref().err().and_then(|e| redirect(&target, e)) {
+                        if !next.asking {
+                            moved = Some(next.node.clone());
+                        }
+                        redirected.push((i, next));
+                    }
+                    // A command redirected too many times fails with its last redirection.
+                    replies[i] = Some(reply);
+                }
+            }
+            if let Some(node) = moved {
+                self.refresh(&node)?;
+            }
+            if redirected.is_empty() {
+                break;
+            }
+            pending = redirected;
+        }
+        Ok(replies
+            .into_iter()
+            .map(|reply| reply.expect("a reply to every command"))
+            .collect())
+    }
+
+    /// Send a pipeline of writes, and return the commands that failed.
+    pub(super) fn send<'a>(&mut self, pipe: &WritePipeline<'a>) -> Result<Vec<Failure<'a>>, Error> {
+        let replies = self.query(pipe)?;
+        Ok(pipe
+            .keys
+            .iter()
+            .zip(replies)
+            .filter_map(|(&key, reply)| {
+                reply.err().map(|error| Failure {
+                    keys: vec![key],
+                    transaction: false,
+                    error,
+                })
+            })
+            .collect())
+    }
+
+    /// Send a pipeline of writes as transactions, one for each slot written to (a transaction
+    /// can't span slots), and return the commands that failed.
+    ///
+    /// A redirected transaction is sent again as a whole to the node it was redirected to.
+    pub(super) fn send_transactions<'a>(
+        &mut self,
+        pipe: WritePipeline<'a>,
+    ) -> Result<Vec<Failure<'a>>, Error> {
+        let mut failures = Vec::new();
+        for (slot, pipe) in pipe.split(|key| key_slot(key)) {
+            let mut target = self.owner(slot);
+            for redirects in 0.. {
+                let conn = self.conn(&target.node)?;
+                if target.asking {
+                    // `ASKING` lasts until `EXEC`, when sent before `MULTI`.
+                    redis::cmd("ASKING").query::<()>(conn)?;
+                }
+                let sent = pipe.send(conn, true)?;
+                match sent
+                    .iter()
+                    .find_map(|failure| redirect(&target, &failure.error))
+                {
+                    Some(next) if redirects < MAX_REDIRECTS => {
+                        if !next.asking {
+                            self.refresh(&next.node)?;
+                        }
+                        target = next;
+                    }
+                    _ => {
+                        failures.extend(sent);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(failures)
+    }
+
+    /// The node serving the slot, as last discovered.
+    ///
+    /// If no node serves it (e.g. while the cluster is reconfigured), the command is sent
+    /// to any node, which redirects or fails it.
+    fn owner(&self, slot: u16) -> Target {
+        let node = self
+            .nodes
+            .iter()
+            .find(|node| node.serves(slot))
+            .unwrap_or(&self.nodes[0]);
+        Target {
+            node: (node.host.clone(), node.port),
+            asking: false,
+        }
+    }
+
+    /// The connection to the node, opened if needed.
+    fn conn(&mut self, node: &Node) -> Result<&mut redis::Connection, Error> {
+        Ok(match self.conns.entry(node.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let url = server_url(&self.seed, &node.0, node.1)?;
+                entry.insert(open_client(&url, &self.tls)?.get_connection()?)
+            }
+        })
+    }
+
+    /// Discover the nodes again, through the given node, after a slot moved.
+    fn refresh(&mut self, node: &Node) -> Result<(), Error> {
+        let host = node.0.clone();
+        let nodes = discover(self.conn(node)?, &host, false)?;
+        if !nodes.is_empty() {
+            self.nodes = nodes;
+        }
+        Ok(())
+    }
+}
+
+/// The node a command sent to the target was redirected to (by a `MOVED` or `ASK` error),
+/// if it was.
+fn redirect(target: &Target, err: &redis::RedisError) -> Option<Target> {
+    let (addr, _slot) = err.redirect_node()?;
+    let (host, port) = addr.rsplit_once(':')?;
+    // A node with an unknown address (`?` or empty) is reached like the one that replied.
+    let host = match host {
+        "" | "?" => target.node.0.clone(),
+        host => host.to_string(),
+    };
+    Some(Target {
+        node: (host, port.parse().ok()?),
+        asking: err.kind() == redis::ErrorKind::Ask,
+    })
+}
+
+/// Read the reply to a command, errors included, unless the connection is lost.
+fn recv(conn: &mut redis::Connection) -> Result<redis::RedisResult<redis::Value>, Error> {
+    match conn.recv_response().and_then(redis::Value::extract_error) {
+        // The connection is lost, so there are no replies left to read.
+        Err(err) if err.is_io_error() => Err(err.into()),
+        reply => Ok(reply),
+    }
+}
@@ -0,0 +1,273 @@
+//! Incremental restore of a dump, read entry by entry.
+//!
+//! Dumps are written with their header first (see [`crate::redis_dump::RedisDump::write_json`]),
+//! so the format version is known before the first entry is read. Dumps that don't start with
+//! their header (legacy v1 dumps, or dumps whose keys were reordered) and dumps of older versions,
+//! which are converted to the current layout, are read in full instead.
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    mem,
+};
+
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+
+use super::{ConflictPolicy, RedisRestore, RestoreStats};
+use crate::{
+    types::{DumpHeader, RedisBytes, RedisDumpFile, RedisEntry, FORMAT_VERSION},
+    Error,
+};
+
+impl RedisRestore {
+    /// Restore a dump from a reader, parsing and writing its entries in batches
+    /// (see [`super::RedisRestoreBuilder::with_batch_size`]).
+    ///
+    /// Memory use doesn't grow with the size of the dump, and keys are written as soon as
+    /// they are read. Dumps of older versions, dumps that don't start with their header, and
+    /// restoring with [`ConflictPolicy::Fail`] (which checks every key before writing any, see
+    /// [`RedisRestore::fill_from_seekable`]) need the whole dump in memory.
+    ///
+    /// Restoring every database of a dump into a cluster fails with
+    /// [`Error::MultipleDatabases`] once a second database with keys is read, after the keys
+    /// of the first one are written.
+    pub fn fill_from_reader<R: Read>(&mut self, reader: R) -> Result<RestoreStats, Error> {
+        if self.conflict_policy == ConflictPolicy::Fail {
+            return self.fill_from_buffer(&mut BufReader::new(reader));
+        }
+        Ok(self.read_streamed(reader, false)?.unwrap_or_default())
+    }
+
+    /// Restore a dump from a reader that can seek, like a file.
+    ///
+    /// This is [`RedisRestore::fill_from_reader`], except that with [`ConflictPolicy::Fail`],
+    /// the dump is read twice rather than held in memory: once to check that none of its keys
+    /// exist, then again to restore them.
+    pub fn fill_from_seekable<R: Read + Seek>(
+        &mut self,
+        mut reader: R,
+    ) -> Result<RestoreStats, Error> {
+        if self.conflict_policy != ConflictPolicy::Fail {
+            return self.fill_from_reader(reader);
+        }
+        let start = reader.stream_position()?;
+        if let Some(stats) = self.read_streamed(&mut reader, true)? {
+            // The dump couldn't be streamed, so it was checked and restored in full.
+            return Ok(stats);
+        }
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(self.read_streamed(reader, false)?.unwrap_or_default())
+    }
+
+    /// Read a dump entry by entry, restoring its entries, or only checking that none of them
+    /// exist yet with `check`.
+    ///
+    /// Dumps that can't be streamed are restored in full (conflicts included). Returns the
+    /// statistics of the restore, if the dump was restored.
+    fn read_streamed<R: Read>(
+        &mut self,
+        reader: R,
+        check: bool,
+    ) -> Result<Option<RestoreStats>, Error> {
+        let (recorded, recording) = (RefCell::new(Vec::new()), Cell::new(true));
+        let mut reader = Recorder {
+            inner: BufReader::new(reader),
+            recorded: &recorded,
+            recording: &recording,
+        };
+        let mut restore = StreamedRestore {
+            rr: self,
+            recording: &recording,
+            stats: RestoreStats::default(),
+            batch: Vec::new(),
+            conflicts: check.then(Vec::new),
+            error: None,
+            buffered: false,
+        };
+        let mut de = serde_json::Deserializer::from_reader(&mut reader);
+        let result = (&mut de).deserialize_map(DumpVisitor(&mut restore));
+        if restore.buffered {
+            // Nothing was restored yet: restore the recorded start and the rest of the dump.
+            let start = io::Cursor::new(recorded.take());
+            return restore
+                .rr
+                .fill_from_buffer(&mut start.chain(reader.inner))
+                .map(Some);
+        }
+        // Errors from the server are reported as they are, rather than as parsing errors.
+        if let Some(err) = restore.error {
+            return Err(err);
+        }
+        result?;
+        de.end()?;
+        match restore.conflicts {
+            Some(conflicts) if !conflicts.is_empty() => Err(Error::Conflict { keys: conflicts }),
+            Some(_) => Ok(None),
+            None => Ok(Some(restore.stats)),
+        }
+    }
+
+    /// Read the whole dump, then restore it.
+    fn fill_from_buffer<R: Read>(&mut self, reader: &mut R) -> Result<RestoreStats, Error> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        self.fill_db(RedisDumpFile::from_slice(&buf)?)
+    }
+}
+
+/// A reader that keeps a copy of what was read, until the dump is known to be streamable.
+struct Recorder<'a, R> {
+    inner: R,
+    recorded: &'a RefCell<Vec<u8>>,
+    recording: &'a Cell<bool>,
+}
+
+impl<R: Read> Read for Recorder<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if self.recording.get() {
+            self.recorded.borrow_mut().extend_from_slice(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// The state of a restore, shared by the visitors of each level of the dump.
+struct StreamedRestore<'a> {
+    rr: &'a mut RedisRestore,
+    recording: &'a Cell<bool>,
+    stats: RestoreStats,
+    /// The entries read, but not restored (or checked) yet.
+    batch: Vec<(RedisBytes, RedisEntry)>,
+    /// The keys that already exist, when only checking that none do.
+    conflicts: Option<Vec<(u32, RedisBytes)>>,
+    /// The first error returned by the server, if any.
+    error: Option<Error>,
+    /// Whether the dump must be read in full instead.
+    buffered: bool,
+}
+
+impl StreamedRestore<'_> {
+    /// Restore (or check) the entries of the current batch, read from the given database.
+    fn flush<E: de::Error>(&mut self, db: u32) -> Result<(), E> {
+        let batch = mem::take(&mut self.batch);
+        let result =
+            self.rr
+                .select_entries_db(db, batch.len())
+                .and_then(|selected| match &mut self.conflicts {
+                    _ if !selected => Ok(()),
+                    Some(conflicts) => self
+                        .rr
+                        .find_conflicts(batch.iter().map(|(key, entry)| (key, entry)), conflicts),
+                    None => self.rr.fill_entries(batch, &mut self.stats),
+                });
+        result.map_err(|err| self.fail(err))
+    }
+
+    /// Keep the error to report it, and abort the parsing.
+    fn fail<E: de::Error>(&mut self, err: Error) -> E {
+        let msg = err.to_string();
+        self.error.get_or_insert(err);
+        E::custom(msg)
+    }
+}
+
+/// Visits the top-level object of the dump: its header, then its databases.
+struct DumpVisitor<'a, 'b>(&'b mut StreamedRestore<'a>);
+
+impl<'de> Visitor<'de> for DumpVisitor<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a redis dump")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let restore = self.0;
+        if map.next_key::<String>()?.as_deref() != Some("header") {
+            restore.buffered = true;
+            return Err(de::Error::custom("the dump doesn't start with its header"));
+        }
+        match map.next_value::<DumpHeader>() {
+            Ok(header) if header.format_version == FORMAT_VERSION => {}
+            // A legacy key named `header`, or another version: let the full parser tell.
+            _ => {
+                restore.buffered = true;
+                return Err(de::Error::custom("the dump isn't in the current format"));
+            }
+        }
+        restore.recording.set(false);
+
+        while let Some(field) = map.next_key::<String>()? {
+            if field == "databases" {
+                map.next_value_seed(DatabasesVisitor(&mut *restore))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Visits the databases of the dump, by index.
+struct DatabasesVisitor<'a, 'b>(&'b mut StreamedRestore<'a>);
+
+impl<'de> DeserializeSeed<'de> for DatabasesVisitor<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for DatabasesVisitor<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of databases")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(db) = map.next_key::<u32>()? {
+            map.next_value_seed(EntriesVisitor(&mut *self.0, db))?;
+        }
+        Ok(())
+    }
+}
+
+/// Visits the entries of a single database, restoring them in batches.
+struct EntriesVisitor<'a, 'b>(&'b mut StreamedRestore<'a>, u32);
+
+impl<'de> DeserializeSeed<'de> for EntriesVisitor<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for EntriesVisitor<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of entries")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let EntriesVisitor(restore, db) = self;
+        if !restore.rr.is_restored_db(db) {
+            while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {
+                restore.stats.filtered += 1;
+            }
+            return Ok(());
+        }
+
+        while let Some(entry) = map.next_entry::<RedisBytes, RedisEntry>()? {
+            restore.batch.push(entry);
+            if restore.batch.len() >= restore.rr.batch_size {
+                restore.flush(db)?;
+            }
+        }
+        restore.flush(db)
+    }
+}
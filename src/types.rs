@@ -1,22 +1,428 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    ops::Deref,
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::Error;
+
+pub mod v1;
+pub mod v2;
+
+/// The version of the dump format written by this crate.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Prefix of base64 encoded bytes in a dump.
+const BASE64_PREFIX: &str = "base64:";
+/// Prefix of hex encoded bytes in a dump.
+const HEX_PREFIX: &str = "hex:";
+
+/// Binary-safe bytes, as stored by Redis (key names, values, members, fields...).
+///
+/// Serialized as a plain string when the bytes are valid UTF-8, and as a base64 string
+/// prefixed with `base64:` otherwise. Strings prefixed with `hex:` are read as hex.
+/// UTF-8 strings that happen to start with one of these prefixes are base64 encoded as
+/// well, so every string in a dump has exactly one meaning.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RedisBytes(pub Vec<u8>);
+
+impl Deref for RedisBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for RedisBytes {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for RedisBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<String> for RedisBytes {
+    fn from(string: String) -> Self {
+        Self(string.into_bytes())
+    }
+}
+
+impl From<&str> for RedisBytes {
+    fn from(string: &str) -> Self {
+        Self(string.as_bytes().to_vec())
+    }
+}
+
+impl fmt::Display for RedisBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl redis::ToRedisArgs for RedisBytes {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + redis::RedisWrite,
+    {
+        out.write_arg(&self.0)
+    }
+}
+
+impl redis::FromRedisValue for RedisBytes {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        Vec::<u8>::from_redis_value(v).map(Self)
+    }
+}
+
+impl Serialize for RedisBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(&self.0) {
+            Ok(s) if !s.starts_with(BASE64_PREFIX) && !s.starts_with(HEX_PREFIX) => {
+                serializer.serialize_str(s)
+            }
+            _ => serializer.serialize_str(&format!("{}{}", BASE64_PREFIX, base64::encode(&self.0))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RedisBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RedisBytesVisitor;
+
+        impl<'de> de::Visitor<'de> for RedisBytesVisitor {
+            type Value = RedisBytes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string, optionally `base64:` or `hex:` encoded")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<RedisBytes, E> {
+                if let Some(encoded) = s.strip_prefix(BASE64_PREFIX) {
+                    base64::decode(encoded).map(RedisBytes).map_err(E::custom)
+                } else if let Some(encoded) = s.strip_prefix(HEX_PREFIX) {
+                    hex::decode(encoded).map(RedisBytes).map_err(E::custom)
+                } else {
+                    Ok(RedisBytes::from(s))
+                }
+            }
+        }
+
+        deserializer.deserialize_str(RedisBytesVisitor)
+    }
+}
+
+/// A Redis value, tagged with its key type.
+///
+/// Serialized as `{"type": "<key type>", "data": <value>}`.
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
 pub enum RedisValue {
-    String(String),
-    Hash(HashMap<String, String>),
-    List(Vec<String>),
-    Set(HashSet<String>),
-    ZSet(Vec<(String, f32)>),
-    Meta(RedisMeta),
+    String(RedisBytes),
+    Hash(HashMap<RedisBytes, RedisBytes>),
+    List(Vec<RedisBytes>),
+    Set(HashSet<RedisBytes>),
+    ZSet(#[serde(with = "zset_scores")] Vec<(RedisBytes, f64)>),
+    Stream(RedisStream),
+    /// A key of any type, as serialized by `DUMP`.
+    Raw(RedisPayload),
+}
+
+impl RedisValue {
+    /// The type of the key holding the value, as returned by `TYPE`.
+    pub fn key_type(&self) -> &str {
+        match self {
+            RedisValue::String(_) => "string",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::List(_) => "list",
+            RedisValue::Set(_) => "set",
+            RedisValue::ZSet(_) => "zset",
+            RedisValue::Stream(_) => "stream",
+            RedisValue::Raw(raw) => &raw.key_type,
+        }
+    }
+}
+
+/// The `DUMP` serialization of a key, which `RESTORE` recreates exactly.
+///
+/// Works for every key type, including the types of modules.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedisPayload {
+    /// The type of the key, as returned by `TYPE`.
+    pub key_type: String,
+    /// The payload returned by `DUMP`, always base64 encoded.
+    #[serde(with = "base64_bytes")]
+    pub payload: Vec<u8>,
+}
+
+/// (De)serializes bytes as a base64 string.
+mod base64_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        base64::decode(String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// A stream, along with its consumer groups.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedisStream {
+    pub entries: Vec<RedisStreamEntry>,
+    /// The last ID generated for the stream, which may be greater than the ID of its last
+    /// entry (if entries were deleted).
+    pub last_id: String,
+    /// The number of entries ever added to the stream, deleted ones included.
+    ///
+    /// This and [`RedisStream::max_deleted_entry_id`] are used to compute the lag of consumer
+    /// groups, and are only kept by Redis 7 or later.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entries_added: Option<u64>,
+    /// The greatest ID of the entries deleted from the stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_deleted_entry_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<RedisStreamGroup>,
 }
 
+/// A stream entry, with its fields in insertion order.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct RedisMeta {
-    pub(crate) db: u32,
-    pub(crate) r#type: String,
-    pub(crate) ttl: i64,
-    pub(crate) data: Box<RedisValue>,
+pub struct RedisStreamEntry {
+    pub id: String,
+    pub fields: Vec<(RedisBytes, RedisBytes)>,
+}
+
+/// A stream consumer group.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedisStreamGroup {
+    pub name: RedisBytes,
+    pub last_delivered_id: String,
+    /// The number of entries of the stream read by the group, if known (Redis 7 or later).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entries_read: Option<u64>,
+    pub consumers: Vec<RedisBytes>,
+    /// The pending entries list (PEL) of the group: entries delivered to a consumer,
+    /// but not yet acknowledged. Pending entries that were deleted from the stream aren't
+    /// restored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<RedisStreamPending>,
+}
+
+/// A pending entry of a stream consumer group.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedisStreamPending {
+    pub id: String,
+    pub consumer: RedisBytes,
+    /// Milliseconds since the entry was last delivered, at the time of the dump.
+    pub idle: u64,
+    pub delivery_count: u64,
+}
+
+/// (De)serializes sorted set scores.
+///
+/// Scores are written as JSON numbers, which keep their full `f64` precision.
+/// JSON has no infinity, so `+inf` and `-inf` scores are written as the strings
+/// `"inf"` and `"-inf"`, like Redis does.
+mod zset_scores {
+    use std::fmt;
+
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::RedisBytes;
+
+    struct Score(f64);
+
+    impl Serialize for Score {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self.0 {
+                score if score == f64::INFINITY => serializer.serialize_str("inf"),
+                score if score == f64::NEG_INFINITY => serializer.serialize_str("-inf"),
+                score => serializer.serialize_f64(score),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Score {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct ScoreVisitor;
+
+            impl<'de> de::Visitor<'de> for ScoreVisitor {
+                type Value = Score;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    write!(f, "a number, `inf` or `-inf`")
+                }
+
+                fn visit_f64<E: de::Error>(self, v: f64) -> Result<Score, E> {
+                    Ok(Score(v))
+                }
+
+                fn visit_i64<E: de::Error>(self, v: i64) -> Result<Score, E> {
+                    Ok(Score(v as f64))
+                }
+
+                fn visit_u64<E: de::Error>(self, v: u64) -> Result<Score, E> {
+                    Ok(Score(v as f64))
+                }
+
+                fn visit_str<E: de::Error>(self, v: &str) -> Result<Score, E> {
+                    match v {
+                        "inf" | "+inf" => Ok(Score(f64::INFINITY)),
+                        "-inf" => Ok(Score(f64::NEG_INFINITY)),
+                        _ => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+                    }
+                }
+            }
+
+            deserializer.deserialize_any(ScoreVisitor)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(
+        zset: &[(RedisBytes, f64)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(zset.iter().map(|(member, score)| (member, Score(*score))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(RedisBytes, f64)>, D::Error> {
+        Ok(Vec::<(RedisBytes, Score)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(member, score)| (member, score.0))
+            .collect())
+    }
+}
+
+/// A dumped key: its value, and its metadata if it was dumped with metadata.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedisEntry {
+    #[serde(flatten)]
+    pub value: RedisValue,
+    /// The time-to-live of the key in milliseconds when it was dumped, as returned by `PTTL`
+    /// (`-1` if the key has no expiry).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pttl: Option<i64>,
+    /// When the key expires, as a Unix timestamp in milliseconds (by the clock of the server).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expire_at: Option<u64>,
+    /// The hash slot of the key, if it was dumped from a cluster.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<u16>,
+}
+
+/// The entries of a single database, by key name.
+pub type RedisEntries = HashMap<RedisBytes, RedisEntry>;
+
+/// Information about the server a dump was taken from.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub redis_version: Option<String>,
+    pub redis_mode: Option<String>,
+    pub os: Option<String>,
+}
+
+/// The header of a dump file, describing how and where it was made.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpHeader {
+    pub format_version: u32,
+    /// The version of the tool that wrote the dump.
+    pub tool_version: String,
+    pub server: ServerInfo,
+    /// When the dump was started, as a Unix timestamp in milliseconds.
+    pub created_at: u64,
+    /// What happened to keys that changed during the dump, if known.
+    ///
+    /// This is only known once every key is read, so it is only written into dumps written
+    /// to a file (see [`crate::redis_dump::RedisDump::write_json_seekable`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<DumpSummary>,
+}
+
+/// Keys that changed while they were dumped.
+///
+/// A key can be deleted, expire or be overwritten with another type between the time it is
+/// scanned and the time it is read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpSummary {
+    /// The number of keys skipped because they were deleted (or expired) before they were read.
+    pub vanished: usize,
+    /// The number of times a key was read again because its type changed while it was read.
+    pub retyped: usize,
+}
+
+/// A key that couldn't be dumped or restored, when keeping going after errors.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyError {
+    pub key: RedisBytes,
+    /// The database of the key.
+    pub db: u32,
+    /// The type of the key.
+    #[serde(rename = "type")]
+    pub key_type: String,
+    /// Why the key failed, as reported by the server (or the tool).
+    pub reason: String,
+}
+
+/// The content of a dump file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedisDumpFile {
+    pub header: DumpHeader,
+    /// The dumped entries, namespaced by their database index.
+    pub databases: BTreeMap<u32, RedisEntries>,
+}
+
+impl RedisDumpFile {
+    /// Parse a dump file, detecting its format version.
+    ///
+    /// Dumps of older versions (including legacy v1 dumps, which have no header) are
+    /// converted to the current layout.
+    pub fn from_slice(buf: &[u8]) -> Result<Self, Error> {
+        #[derive(Deserialize)]
+        struct HeaderProbe {
+            format_version: u32,
+        }
+        #[derive(Deserialize)]
+        struct FormatProbe {
+            header: Option<HeaderProbe>,
+        }
+
+        let format_version = serde_json::from_slice::<FormatProbe>(buf)
+            .ok()
+            .and_then(|probe| probe.header)
+            .map_or(1, |header| header.format_version);
+        match format_version {
+            1 => {
+                let databases =
+                    serde_json::from_slice::<v1::RedisDumpData>(buf)?.into_databases()?;
+                Ok(RedisDumpFile {
+                    header: DumpHeader {
+                        format_version: FORMAT_VERSION,
+                        tool_version: String::new(),
+                        server: ServerInfo::default(),
+                        created_at: 0,
+                        summary: None,
+                    },
+                    databases,
+                })
+            }
+            2 => Ok(serde_json::from_slice::<v2::RedisDumpFile>(buf)?.into()),
+            FORMAT_VERSION => Ok(serde_json::from_slice(buf)?),
+            _ => Err(Error::Format(format!(
+                "Unsupported dump format version {} (supported versions: 1-{})",
+                format_version, FORMAT_VERSION
+            ))),
+        }
+    }
 }
@@ -0,0 +1,178 @@
+//! The legacy (v1) dump format.
+//!
+//! v1 dumps have no header, and their values are untagged, so the type of a value is guessed
+//! from its JSON shape (or taken from the per-key metadata, when it was dumped).
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value as Json};
+
+use super::{RedisBytes, RedisEntries, RedisEntry};
+use crate::Error;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RedisValue {
+    String(String),
+    Hash(HashMap<String, String>),
+    List(Vec<String>),
+    Set(HashSet<String>),
+    ZSet(Vec<(String, f64)>),
+    Meta(RedisMeta),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedisMeta {
+    pub(crate) db: u32,
+    pub(crate) r#type: String,
+    pub(crate) ttl: i64,
+    pub(crate) data: Box<RedisValue>,
+}
+
+/// The entries of a single database, by key name.
+pub type RedisEntriesV1 = HashMap<String, RedisValue>;
+
+/// The content of a v1 dump file.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum RedisDumpData {
+    /// Entries namespaced by their database index, e.g. `{"0": {...}, "3": {...}}`.
+    ///
+    /// This is the layout used when dumping more than one database, so keys with
+    /// the same name in different databases don't overwrite each other.
+    Databases(BTreeMap<u32, RedisEntriesV1>),
+    /// A flat map of entries, as produced by a single database dump.
+    Entries(RedisEntriesV1),
+}
+
+/// The layout is told from the shape of the values, since a flat dump may have numeric key
+/// names too: a dump is only namespaced if all its keys are database indices, and some of
+/// its values can't be an entry.
+///
+/// The flat layout is the original one, so an ambiguous dump, whose databases hold nothing
+/// but strings dumped without metadata (`{"1": {"a": "b"}}`), is read as a flat dump of hashes.
+impl<'de> Deserialize<'de> for RedisDumpData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let dump = Map::<String, Json>::deserialize(deserializer)?;
+        let namespaced =
+            dump.keys().all(|key| key.parse::<u32>().is_ok()) && !dump.values().all(is_entry);
+        if !namespaced {
+            return serde_json::from_value(Json::Object(dump))
+                .map(RedisDumpData::Entries)
+                .map_err(D::Error::custom);
+        }
+        dump.into_iter()
+            .map(|(db, entries)| {
+                let db = db.parse::<u32>().expect("a database index");
+                serde_json::from_value(entries)
+                    .map(|entries| (db, entries))
+                    .map_err(D::Error::custom)
+            })
+            .collect::<Result<_, _>>()
+            .map(RedisDumpData::Databases)
+    }
+}
+
+/// Whether the JSON value can be a single entry of a flat dump: a value with metadata,
+/// a hash (whose values are all strings), or anything but an object.
+///
+/// Redis doesn't keep empty hashes, so an empty object is an (empty) database.
+fn is_entry(value: &Json) -> bool {
+    match value {
+        Json::Object(object) => {
+            let meta = ["db", "type", "ttl", "data"];
+            (object.len() == meta.len() && meta.iter().all(|field| object.contains_key(*field)))
+                || (!object.is_empty() && object.values().all(Json::is_string))
+        }
+        _ => true,
+    }
+}
+
+impl RedisDumpData {
+    /// Convert the dump into the current (v2) layout.
+    ///
+    /// Entries of a flat dump go to the database recorded in their metadata,
+    /// or to database 0 if they were dumped without metadata.
+    pub fn into_databases(self) -> Result<BTreeMap<u32, RedisEntries>, Error> {
+        let mut databases = BTreeMap::<u32, RedisEntries>::new();
+        match self {
+            RedisDumpData::Databases(dbs) => {
+                for (db, entries) in dbs {
+                    let converted = databases.entry(db).or_default();
+                    for (key, value) in entries {
+                        let (_, entry) = value.into_entry(&key)?;
+                        converted.insert(key.into(), entry);
+                    }
+                }
+            }
+            RedisDumpData::Entries(entries) => {
+                for (key, value) in entries {
+                    let (db, entry) = value.into_entry(&key)?;
+                    databases.entry(db).or_default().insert(key.into(), entry);
+                }
+            }
+        }
+        Ok(databases)
+    }
+}
+
+impl RedisValue {
+    /// Convert the value into a v2 entry, along with the database it was dumped from.
+    fn into_entry(self, key: &str) -> Result<(u32, RedisEntry), Error> {
+        match self {
+            RedisValue::Meta(meta) => {
+                let value = meta.data.into_typed(key, Some(&meta.r#type))?;
+                Ok((
+                    meta.db,
+                    RedisEntry {
+                        value,
+                        pttl: Some(super::v2::ttl_to_pttl(meta.ttl)),
+                        expire_at: None,
+                        slot: None,
+                    },
+                ))
+            }
+            value => Ok((
+                0,
+                RedisEntry {
+                    value: value.into_typed(key, None)?,
+                    pttl: None,
+                    expire_at: None,
+                    slot: None,
+                },
+            )),
+        }
+    }
+
+    /// Convert an untagged value into a tagged one.
+    ///
+    /// If the key type is known (from the metadata), it wins over the JSON shape of the value:
+    /// sets are serialized as arrays, and come back as lists.
+    fn into_typed(self, key: &str, key_type: Option<&str>) -> Result<super::RedisValue, Error> {
+        Ok(match (self, key_type) {
+            (RedisValue::List(list), Some("set")) => {
+                super::RedisValue::Set(list.into_iter().map(RedisBytes::from).collect())
+            }
+            (RedisValue::String(string), _) => super::RedisValue::String(string.into()),
+            (RedisValue::Hash(hash), _) => super::RedisValue::Hash(
+                hash.into_iter()
+                    .map(|(field, value)| (field.into(), value.into()))
+                    .collect(),
+            ),
+            (RedisValue::List(list), _) => {
+                super::RedisValue::List(list.into_iter().map(RedisBytes::from).collect())
+            }
+            (RedisValue::Set(set), _) => {
+                super::RedisValue::Set(set.into_iter().map(RedisBytes::from).collect())
+            }
+            (RedisValue::ZSet(zset), _) => super::RedisValue::ZSet(
+                zset.into_iter()
+                    .map(|(member, score)| (member.into(), score))
+                    .collect(),
+            ),
+            (RedisValue::Meta(_), _) => {
+                return Err(Error::Format(format!("{}: Nested metadata", key)))
+            }
+        })
+    }
+}
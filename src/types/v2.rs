@@ -0,0 +1,107 @@
+//! The v2 dump format.
+//!
+//! v2 dumps record TTLs in seconds, except for raw payloads which record them in milliseconds
+//! along with the payload, and have no absolute expiry time.
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Deserialize;
+
+use super::{DumpHeader, RedisBytes, RedisEntries, RedisEntry, RedisStream};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+pub enum RedisValue {
+    String(RedisBytes),
+    Hash(HashMap<RedisBytes, RedisBytes>),
+    List(Vec<RedisBytes>),
+    Set(HashSet<RedisBytes>),
+    ZSet(#[serde(with = "super::zset_scores")] Vec<(RedisBytes, f64)>),
+    Stream(RedisStream),
+    Raw(RedisPayload),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedisPayload {
+    key_type: String,
+    #[serde(with = "super::base64_bytes")]
+    payload: Vec<u8>,
+    /// The time-to-live of the key in milliseconds.
+    #[serde(default)]
+    pttl: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedisEntryV2 {
+    #[serde(flatten)]
+    value: RedisValue,
+    /// The time-to-live of the key in seconds.
+    #[serde(default)]
+    ttl: Option<i64>,
+}
+
+/// The content of a v2 dump file.
+#[derive(Debug, Deserialize)]
+pub struct RedisDumpFile {
+    header: DumpHeader,
+    databases: BTreeMap<u32, HashMap<RedisBytes, RedisEntryV2>>,
+}
+
+impl From<RedisDumpFile> for super::RedisDumpFile {
+    fn from(dump: RedisDumpFile) -> Self {
+        let databases = dump
+            .databases
+            .into_iter()
+            .map(|(db, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(key, entry)| (key, entry.into()))
+                    .collect::<RedisEntries>();
+                (db, entries)
+            })
+            .collect();
+        // The converted dump is in the current layout, and is written as such.
+        super::RedisDumpFile {
+            header: DumpHeader {
+                format_version: super::FORMAT_VERSION,
+                ..dump.header
+            },
+            databases,
+        }
+    }
+}
+
+impl From<RedisEntryV2> for RedisEntry {
+    fn from(entry: RedisEntryV2) -> Self {
+        let mut pttl = entry.ttl.map(ttl_to_pttl);
+        let value = match entry.value {
+            RedisValue::String(string) => super::RedisValue::String(string),
+            RedisValue::Hash(hash) => super::RedisValue::Hash(hash),
+            RedisValue::List(list) => super::RedisValue::List(list),
+            RedisValue::Set(set) => super::RedisValue::Set(set),
+            RedisValue::ZSet(zset) => super::RedisValue::ZSet(zset),
+            RedisValue::Stream(stream) => super::RedisValue::Stream(stream),
+            RedisValue::Raw(raw) => {
+                pttl = raw.pttl;
+                super::RedisValue::Raw(super::RedisPayload {
+                    key_type: raw.key_type,
+                    payload: raw.payload,
+                })
+            }
+        };
+        RedisEntry {
+            value,
+            pttl,
+            expire_at: None,
+            slot: None,
+        }
+    }
+}
+
+/// Convert a TTL in seconds to milliseconds, keeping the negative values of `TTL` as they are.
+pub(crate) fn ttl_to_pttl(ttl: i64) -> i64 {
+    if ttl > 0 {
+        ttl * 1000
+    } else {
+        ttl
+    }
+}